@@ -0,0 +1,58 @@
+use std::net::SocketAddr;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+use quick_chat::{ChatClient, ChatServer, ConnectOptions, Encoding, HistoryQuery};
+
+// The most a single fetch hands back
+const HISTORY_SIZE: usize = 1000;
+
+fn query() -> HistoryQuery {
+    HistoryQuery {
+        limit: Some(HISTORY_SIZE),
+        ..HistoryQuery::default()
+    }
+}
+
+fn connect(address: SocketAddr, encoding: Encoding, username: &str) -> ChatClient {
+    let client = ChatClient::connect(&address.to_string(), ConnectOptions {
+        encoding,
+        ..ConnectOptions::default()
+    }).expect("Failed to connect");
+    client.set_username(username).expect("Failed to pick a username");
+    client
+}
+
+// Fills the history of a fresh in-memory server, then times fetching all of it in each encoding
+fn history_sync(c: &mut Criterion) {
+    let server = ChatServer::builder()
+        .bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .disable_discovery()
+        .start()
+        .expect("Failed to start the server");
+    let address = server.local_addr();
+
+    let writer = connect(address, Encoding::Json, "writer");
+    for i in 0..HISTORY_SIZE {
+        writer.send(&format!("Message {} of the backlog, long enough to look like real chat", i))
+            .expect("Failed to send");
+    }
+    while writer.fetch_history(query()).expect("Failed to fetch").len() < HISTORY_SIZE {}
+
+    let mut group = c.benchmark_group("history_sync");
+    group.throughput(Throughput::Elements(HISTORY_SIZE as u64));
+    for encoding in [Encoding::Json, Encoding::MessagePack] {
+        let reader = connect(address, encoding, &format!("reader-{}", encoding));
+        group.bench_function(encoding.to_string(), |b| {
+            b.iter(|| reader.fetch_history(query()).expect("Failed to fetch"))
+        });
+        reader.close();
+    }
+    group.finish();
+
+    writer.close();
+    server.shutdown().expect("Failed to shut the server down");
+}
+
+criterion_group!(benches, history_sync);
+criterion_main!(benches);
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::OsRng;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::message::now_nanos;
+use crate::usernames::canonical;
+
+pub(crate) const MIN_PASSWORD_LEN: usize = 8;
+
+#[derive(Serialize, Deserialize)]
+struct Account {
+    username: String,
+    // PHC string, so the salt and argon2 parameters travel with the hash
+    password_hash: String,
+    created_at: i64,
+}
+
+/// Registered usernames. A registered name can only be taken by logging in with its password,
+/// whether or not its owner is online.
+pub struct Accounts {
+    // Keyed by canonical name, so look-alikes of a registered name count as registered too
+    accounts: HashMap<String, Account>,
+    // Append-only log with one JSON encoded account per line, None to keep accounts in memory only
+    file: Option<File>,
+    allow_guests: bool,
+}
+
+impl Accounts {
+    /// Accounts that are forgotten when the server stops.
+    pub fn in_memory() -> Accounts {
+        Accounts {
+            accounts: HashMap::new(),
+            file: None,
+            allow_guests: true,
+        }
+    }
+
+    /// Loads the accounts kept in `path` and adds new ones to it, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Accounts> {
+        let mut accounts = HashMap::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Account>(&line) {
+                    Ok(account) => {
+                        accounts.insert(canonical(&account.username), account);
+                    }
+                    Err(e) => warn!("Skipping unreadable account on line {} of {}: {}",
+                                    index + 1, path.display(), e),
+                }
+            }
+        }
+        debug!("Loaded {} accounts from {}", accounts.len(), path.display());
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(Accounts {
+            accounts,
+            file: Some(options.open(path)?),
+            allow_guests: true,
+        })
+    }
+
+    /// Without guests every username has to be registered before it can be used.
+    pub fn allow_guests(&mut self, allow_guests: bool) {
+        self.allow_guests = allow_guests;
+    }
+
+    pub(crate) fn guests_allowed(&self) -> bool {
+        self.allow_guests
+    }
+
+    pub(crate) fn is_registered(&self, username: &str) -> bool {
+        self.accounts.contains_key(&canonical(username))
+    }
+
+    // The name as it was registered, which is what a login under any spelling of it gets
+    pub(crate) fn registered_name(&self, username: &str) -> Option<String> {
+        self.accounts.get(&canonical(username)).map(|account| account.username.clone())
+    }
+
+    pub(crate) fn password_hash(&self, username: &str) -> Option<String> {
+        self.accounts.get(&canonical(username)).map(|account| account.password_hash.clone())
+    }
+
+    pub(crate) fn add(&mut self, username: &str, password_hash: String) -> io::Result<()> {
+        let account = Account {
+            username: username.to_string(),
+            password_hash,
+            created_at: now_nanos(),
+        };
+        if let Some(file) = self.file.as_mut() {
+            let mut line = serde_json::to_vec(&account)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            file.sync_all()?;
+        }
+        self.accounts.insert(canonical(&account.username), account);
+        Ok(())
+    }
+}
+
+// Hashing is deliberately slow, call these off the async workers
+pub(crate) fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(e) => {
+            warn!("Stored password hash is unreadable: {}", e);
+            false
+        }
+    }
+}
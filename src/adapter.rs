@@ -1,5 +1,4 @@
 use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
 
 pub(crate) struct Adapter {
     name: String,
@@ -8,27 +7,81 @@ pub(crate) struct Adapter {
 }
 
 impl Adapter {
-    pub(crate) fn broadcast_address(&mut self) -> Option<IpAddr> {
-        if self.ipv4_address.is_none() || self.subnet_mask.is_none() {
+    pub(crate) fn broadcast_address(&self) -> Option<IpAddr> {
+        let ip_address = u32::from(self.ipv4_address?);
+        let subnet_mask = u32::from(self.subnet_mask?);
+
+        // Point to point and host routes have no broadcast address of their own
+        if subnet_mask == u32::MAX {
             return None;
         }
 
-        let ip_address = self.ipv4_address.unwrap().octets();
-        let subnet_mask = self.subnet_mask.unwrap().octets();
+        Some(IpAddr::V4(Ipv4Addr::from(ip_address | !subnet_mask)))
+    }
+
+    pub(crate) fn get_adapter_name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn get_adapters() -> Vec<Adapter> {
+    use std::ffi::CStr;
+
+    use log::error;
+
+    let mut adapters = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // SAFETY: getifaddrs fills in a linked list that stays valid until freeifaddrs is called
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        error!("Failed to list network adapters: {}", std::io::Error::last_os_error());
+        return adapters;
+    }
+
+    let mut current = ifaddrs;
+    while !current.is_null() {
+        // SAFETY: current is a non-null node of the list returned by getifaddrs
+        let ifaddr = unsafe { &*current };
+        current = ifaddr.ifa_next;
 
-        let mut broadcast_address = [0; 4];
-        for i in 0..4 {
-            broadcast_address[i] = ip_address[i] | !subnet_mask[i];
+        if ifaddr.ifa_addr.is_null() || ifaddr.ifa_flags & libc::IFF_LOOPBACK as libc::c_uint != 0 {
+            continue;
         }
+        // SAFETY: ifa_addr was checked to be non-null above
+        if unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int != libc::AF_INET {
+            continue;
+        }
+
+        let name = unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_string_lossy().to_string();
+        let ipv4_address = unsafe { sockaddr_to_ipv4(ifaddr.ifa_addr) };
+        let subnet_mask = unsafe { sockaddr_to_ipv4(ifaddr.ifa_netmask) };
 
-        Some(IpAddr::from(broadcast_address))
+        adapters.push(Adapter {
+            name,
+            ipv4_address,
+            subnet_mask,
+        });
     }
-    pub(crate) fn get_adapter_name(&self) -> String {
-        self.name.clone()
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    adapters
+}
+
+// SAFETY: addr must be null or point to a sockaddr of family AF_INET
+#[cfg(unix)]
+unsafe fn sockaddr_to_ipv4(addr: *const libc::sockaddr) -> Option<Ipv4Addr> {
+    if addr.is_null() {
+        return None;
     }
+    let addr = &*(addr as *const libc::sockaddr_in);
+    Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
 }
 
+#[cfg(windows)]
 pub(crate) fn get_adapters() -> Vec<Adapter> {
+    use std::process::Command;
+
     // Run ipconfig
     let output = Command::new("ipconfig")
         .output()
@@ -44,7 +97,7 @@ pub(crate) fn get_adapters() -> Vec<Adapter> {
         if line.ends_with(":") {
             // If there is an adapter in the current_adapter variable, push it to the adapters vector
             if let Some(adapter) = current_adapter {
-                if !adapter.ipv4_address.is_none() {
+                if adapter.ipv4_address.is_some() {
                     adapters.push(adapter);
                 }
             }
@@ -58,24 +111,22 @@ pub(crate) fn get_adapters() -> Vec<Adapter> {
         }
         if line.contains("IPv4 Address") {
             let ip_address = line.split(":").nth(1).unwrap().trim();
-            let ip_address = ip_address.split("(").nth(0).unwrap().trim();
-            match ip_address.parse::<Ipv4Addr>() {
-                Ok(ip) => current_adapter.as_mut().unwrap().ipv4_address = Some(ip),
-                Err(_) => {}
+            let ip_address = ip_address.split("(").next().unwrap().trim();
+            if let Ok(ip) = ip_address.parse::<Ipv4Addr>() {
+                current_adapter.as_mut().unwrap().ipv4_address = Some(ip);
             }
         }
         if line.contains("Subnet Mask") {
             let ip_address = line.split(":").nth(1).unwrap().trim();
-            let ip_address = ip_address.split("(").nth(0).unwrap().trim();
-            match ip_address.parse::<Ipv4Addr>() {
-                Ok(ip) => current_adapter.as_mut().unwrap().subnet_mask = Some(ip),
-                Err(_) => {}
+            let ip_address = ip_address.split("(").next().unwrap().trim();
+            if let Ok(ip) = ip_address.parse::<Ipv4Addr>() {
+                current_adapter.as_mut().unwrap().subnet_mask = Some(ip);
             }
         }
     }
 
     if let Some(adapter) = current_adapter {
-        if !adapter.ipv4_address.is_none() {
+        if adapter.ipv4_address.is_some() {
             adapters.push(adapter);
         }
     }
@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use quick_chat::{DEFAULT_DISCOVERY_PORT, DEFAULT_QUEUE_SIZE, DEFAULT_SERVER_PORT, Encoding, OverflowPolicy};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Run a headless server
+    Serve {
+        /// Address to accept chat connections on
+        #[arg(long, default_value = "0.0.0.0:42069")]
+        bind: SocketAddr,
+
+        /// UDP port to answer discovery broadcasts on
+        #[arg(long, default_value_t = DEFAULT_DISCOVERY_PORT)]
+        discovery_port: u16,
+
+        /// File to persist chat history in, history is kept in memory only if omitted
+        #[arg(long)]
+        history: Option<PathBuf>,
+
+        /// File to keep registered accounts in, accounts are kept in memory only if omitted
+        #[arg(long)]
+        accounts: Option<PathBuf>,
+
+        /// Only admit registered users, guests can't pick a name of their own
+        #[arg(long)]
+        require_login: bool,
+
+        /// Frames each client may have waiting to be sent before the overflow policy kicks in
+        #[arg(long, default_value_t = DEFAULT_QUEUE_SIZE, value_parser = clap::value_parser!(u32).range(1..))]
+        queue_size: u32,
+
+        /// What to do with a client whose outbound queue is full
+        #[arg(long, value_enum, default_value_t = OverflowPolicy::default())]
+        overflow: OverflowPolicy,
+
+        /// Accept TLS connections alongside plaintext ones
+        #[arg(long)]
+        tls: bool,
+
+        /// Only accept TLS connections
+        #[arg(long)]
+        require_tls: bool,
+
+        /// PEM certificate to serve, a self-signed one is generated on first run if omitted
+        #[arg(long, requires = "key")]
+        cert: Option<PathBuf>,
+
+        /// PEM private key belonging to --cert
+        #[arg(long, requires = "cert")]
+        key: Option<PathBuf>,
+    },
+    /// Connect to a server at a known address
+    Connect {
+        /// Server address, e.g. 192.168.1.10:42069
+        address: String,
+
+        /// UDP port used to look for the server again if it moves while reconnecting
+        #[arg(long, default_value_t = DEFAULT_DISCOVERY_PORT)]
+        discovery_port: u16,
+
+        /// Encrypt the connection, the server's certificate is pinned the first time it is seen
+        #[arg(long)]
+        tls: bool,
+
+        /// How to ask the server to encode messages, JSON is used if it does not speak it
+        #[arg(long, value_enum, default_value_t = Encoding::MessagePack)]
+        encoding: Encoding,
+
+        /// Username to claim instead of prompting for one
+        #[arg(long)]
+        username: Option<String>,
+
+        /// Register the username as an account, prompting for its password
+        #[arg(long)]
+        register: bool,
+    },
+    /// Discover a server on the LAN, or host one if none answers (the default)
+    Auto {
+        /// Port to host the server on if none is found
+        #[arg(long, default_value_t = DEFAULT_SERVER_PORT)]
+        port: u16,
+
+        /// UDP port used for discovery broadcasts
+        #[arg(long, default_value_t = DEFAULT_DISCOVERY_PORT)]
+        discovery_port: u16,
+
+        /// Refuse servers that do not offer TLS, and offer it when hosting one
+        #[arg(long)]
+        tls: bool,
+
+        /// How to ask the server to encode messages, JSON is used if it does not speak it
+        #[arg(long, value_enum, default_value_t = Encoding::MessagePack)]
+        encoding: Encoding,
+
+        /// Username to claim instead of prompting for one
+        #[arg(long)]
+        username: Option<String>,
+
+        /// Register the username as an account, prompting for its password
+        #[arg(long)]
+        register: bool,
+    },
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Auto {
+            port: DEFAULT_SERVER_PORT,
+            discovery_port: DEFAULT_DISCOVERY_PORT,
+            tls: false,
+            encoding: Encoding::MessagePack,
+            username: None,
+            register: false,
+        }
+    }
+}
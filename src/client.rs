@@ -1,233 +1,853 @@
-use std::{io, thread};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
-use std::process::exit;
+use std::collections::HashSet;
+use std::io;
+use std::io::BufWriter;
 use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, sync_channel};
-use std::thread::JoinHandle;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::time::Duration;
 
-use lazy_static::lazy_static;
 use log::{debug, error, trace};
-use serde_json::json;
+use uuid::Uuid;
 
-use crate::client_handler::Messages;
-use crate::message::Message;
+use crate::DEFAULT_DISCOVERY_PORT;
+use crate::client_handler::{HEARTBEAT_INTERVAL, MAX_MISSED_HEARTBEATS};
+use crate::connection::{Connection, Reader, Transport};
+use crate::e2e;
+use crate::e2e::Identity;
+use crate::encoding::Encoding;
+use crate::frame::{FrameReader, write_frame};
+use crate::history::HistoryQuery;
+use crate::message::{DEFAULT_ROOM, Message};
 use crate::message_types::MessageType;
+use crate::presence::Presence;
+use crate::tls::Trust;
+use crate::usernames;
 
-lazy_static! {
-    static ref MESSAGES: Messages = Arc::new(Mutex::new(Vec::new()));
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// Messages asked for per request when catching up on what was missed
+const CATCH_UP_PAGE: usize = 100;
+
+/// How [`ChatClient::connect`] reaches the server.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Encrypt the connection. The server's certificate is pinned the first time it is seen and
+    /// any later change is refused. Reconnecting only accepts the certificate the session started
+    /// with, also when discovery finds the server at another address.
+    pub tls: bool,
+    /// UDP port used to look for the server again if it moves while reconnecting.
+    pub discovery_port: u16,
+    /// What to ask the server to encode messages as. Servers that do not speak it answer in JSON.
+    pub encoding: Encoding,
 }
 
-pub(crate) struct Client {
-    username: String,
-    server_socket: TcpStream,
-    buffer_writer: BufWriter<TcpStream>,
-    receiver: Option<Receiver<Message>>,
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            tls: false,
+            discovery_port: DEFAULT_DISCOVERY_PORT,
+            encoding: Encoding::MessagePack,
+        }
+    }
 }
 
-impl Client {
-    pub(crate) fn new(server_socket: TcpStream) -> Client {
-        let buffer_writer = BufWriter::new(
-            server_socket
-                .try_clone()
-                .expect("Failed to create client BufWriter"));
+/// Why a [`ChatClient`] call failed.
+#[derive(Debug)]
+pub enum ChatError {
+    /// The connection could not be made, or closed while waiting for the server to answer.
+    Io(io::Error),
+    /// The username belongs to a registered account. Call [`ChatClient::login`] with its password.
+    LoginRequired(String),
+    /// The request was turned down, by the server or by a check before it was sent. Holds the reason.
+    Rejected(String),
+}
 
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChatError::Io(e) => write!(f, "{}", e),
+            ChatError::LoginRequired(username) => write!(f, "{} is a registered account, log in to use it", username),
+            ChatError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl From<io::Error> for ChatError {
+    fn from(e: io::Error) -> Self {
+        ChatError::Io(e)
+    }
+}
 
-        Client {
-            username: "".to_string(),
-            server_socket,
-            buffer_writer,
-            receiver: None,
+/// Something that happened on a [`ChatClient`], handed to every subscriber.
+#[derive(Clone)]
+pub enum Event {
+    /// A message meant for the user: chat, direct messages, joins and leaves, renames, replies to
+    /// room and `who` requests, and errors or notices from the server. Encrypted direct messages
+    /// arrive already decrypted, or saying why they could not be.
+    Message(Box<Message>),
+    /// Who is online now. Sent whenever someone connects, leaves or changes name.
+    Users(Vec<Presence>),
+    /// The connection dropped and the client is trying to get it back. Messages sent meanwhile
+    /// are held and go out once it has.
+    Reconnecting,
+    /// The session was resumed on a new connection, `held` messages written while offline were sent.
+    Reconnected { held: usize },
+    /// A reconnect attempt reached the server but could not resume the session. Retrying continues.
+    ResumeFailed(String),
+    /// The connection is gone for good, closed on purpose or lost before a username was set.
+    Disconnected,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Event::Message(message) => write!(f, "{}", message),
+            Event::Users(users) => write!(f, "{} users online", users.len()),
+            Event::Reconnecting => write!(f, "Lost connection to the server, reconnecting..."),
+            Event::Reconnected { held: 0 } => write!(f, "Reconnected"),
+            Event::Reconnected { held } => write!(f, "Reconnected, sent {} messages written while offline", held),
+            Event::ResumeFailed(reason) => write!(f, "Could not resume session: {}", reason),
+            Event::Disconnected => write!(f, "Disconnected from server"),
         }
     }
+}
+
+/// A connection to a QuickChat server.
+///
+/// Messages are received on a background thread from the moment the client connects. Use
+/// [`subscribe`](ChatClient::subscribe) to get them, then pick a name with
+/// [`set_username`](ChatClient::set_username), [`login`](ChatClient::login) or
+/// [`register`](ChatClient::register). A dropped connection is reconnected and the session resumed
+/// on its own.
+///
+/// ```no_run
+/// use quick_chat::{ChatClient, ConnectOptions, Event};
+///
+/// let client = ChatClient::connect("192.168.1.10:42069", ConnectOptions::default())?;
+/// let events = client.subscribe();
+/// client.set_username("greeter")?;
+/// for event in events {
+///     if let Event::Message(message) = event {
+///         println!("{}", message);
+///     }
+/// }
+/// # Ok::<(), quick_chat::ChatError>(())
+/// ```
+pub struct ChatClient {
+    session: Arc<Session>,
+    // Answers to requests, locked for a whole exchange so concurrent requests can't take each
+    // other's replies
+    replies: Mutex<Receiver<Message>>,
+}
 
-    pub(crate) fn run(&mut self) {
-        trace!("Client is running");
-        self.receive_from_server();
-        self.set_username();
-        let username = self.username.clone();
+// State shared with the receive and heartbeat threads
+struct Session {
+    connection: Connection,
+    // Everything sent and received, for transcripts
+    messages: Mutex<Vec<Message>>,
+    // Sequence number of the newest chat message received from the server
+    last_seen: Mutex<Option<u64>>,
+    seen_ids: Mutex<HashSet<Uuid>>,
+    // Rooms in the order they were joined, the last one is where `send` goes
+    joined_rooms: Mutex<Vec<String>>,
+    // Latest user list pushed by the server
+    online_users: Mutex<Vec<Presence>>,
+    // Timestamp of the last `ping`, so its pong can be told apart from heartbeat ones
+    ping_sent: Mutex<Option<i64>>,
+    subscribers: Mutex<Vec<Sender<Event>>>,
+    // Collects every chat message that arrives while `fetch_history` waits for the server
+    fetched: Mutex<Option<Vec<Message>>>,
+}
 
-        // fetch messages from server
-        self.fetch_messages();
+impl Session {
+    fn emit(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
 
-        // send join message
-        self.send_message({
-            &Message::builder()
-                .username(&username)
-                .message_type(MessageType::Join)
-                .build()
+    // What was collected while waiting on a fetch, without the live traffic and join history that
+    // arrived alongside it and does not match `query`
+    fn take_fetched(&self, query: &HistoryQuery) -> Vec<Message> {
+        let mut fetched = self.fetched.lock().unwrap().take().unwrap_or_default();
+        fetched.sort_by_key(Message::get_seq);
+        fetched.dedup_by_key(|message| message.get_id());
+        query.select(&fetched, &|_| true)
+    }
+
+    fn record(&self, message: &Message) {
+        trace!("Sending {}", message);
+        self.messages.lock().unwrap().push(message.clone());
+    }
+
+    // Gives our own copy of a sent message the identity the server stored it under, so it is
+    // recognised when a history fetch brings it back
+    fn accept(&self, accepted: &Message) {
+        self.seen_ids.lock().unwrap().insert(accepted.get_id());
+        let mut messages = self.messages.lock().unwrap();
+        let sent = messages.iter_mut().rev()
+            .find(|message| message.get_id().is_nil() && message.get_timestamp() == accepted.get_timestamp());
+        if let Some(sent) = sent {
+            sent.restore_id(accepted.get_id(), accepted.get_seq());
+        }
+    }
+}
+
+impl ChatClient {
+    /// Connects to the server at `address`, e.g. `192.168.1.10:42069`, and starts receiving.
+    ///
+    /// The key pair used for encrypted direct messages is loaded from `~/.quick_chat/identity.key`
+    /// and created there on first use.
+    pub fn connect(address: &str, options: ConnectOptions) -> Result<ChatClient, ChatError> {
+        let transport = Transport::connect(address, options.tls.then_some(Trust::FirstUse), options.encoding)?;
+        let identity = Identity::load_or_create()?;
+        let session = Arc::new(Session {
+            connection: Connection::new(transport, address, options.discovery_port, options.encoding, identity),
+            messages: Mutex::new(Vec::new()),
+            last_seen: Mutex::new(None),
+            seen_ids: Mutex::new(HashSet::new()),
+            joined_rooms: Mutex::new(vec![DEFAULT_ROOM.to_string()]),
+            online_users: Mutex::new(Vec::new()),
+            ping_sent: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            fetched: Mutex::new(None),
         });
+        let (sender, receiver) = channel();
+        receive_from_server(session.clone(), sender);
+        start_heartbeat(session.clone());
+        Ok(ChatClient {
+            session,
+            replies: Mutex::new(receiver),
+        })
+    }
 
-        let mut msg = String::new();
-        loop {
-            msg.clear();
-            io::stdin()
-                .read_line(&mut msg)
-                .expect("Failed to read line")
-                .to_string();
-            msg = msg.trim().to_string();
-            if msg == "exit" {
-                self.server_socket.shutdown(std::net::Shutdown::Both).unwrap();
-                break;
-            }
+    /// Returns a channel that gets every [`Event`] from now on. Subscribe before picking a name to
+    /// see the messages missed since last time, which arrive right after it.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.session.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
 
+    /// Takes `username` as a guest. Fails with [`ChatError::LoginRequired`] if it is a registered
+    /// account.
+    pub fn set_username(&self, username: &str) -> Result<(), ChatError> {
+        let username = usernames::validate(username).map_err(ChatError::Rejected)?;
+        self.claim(Message::builder()
+            .username(&username)
+            .message_type(MessageType::SetUsername)
+            .build(), None)
+    }
+
+    /// Logs in to a registered account.
+    pub fn login(&self, username: &str, password: &str) -> Result<(), ChatError> {
+        self.claim(Message::builder()
+            .username(username)
+            .message(password)
+            .message_type(MessageType::Login)
+            .build(), Some(password))
+    }
+
+    /// Registers `username` as an account protected by `password` and logs in to it.
+    pub fn register(&self, username: &str, password: &str) -> Result<(), ChatError> {
+        let username = usernames::validate(username).map_err(ChatError::Rejected)?;
+        self.claim(Message::builder()
+            .username(&username)
+            .message(password)
+            .message_type(MessageType::Register)
+            .build(), Some(password))
+    }
+
+    /// Switches to another name while connected. Everyone online is told about the rename.
+    pub fn change_username(&self, username: &str) -> Result<(), ChatError> {
+        let username = usernames::validate(username).map_err(ChatError::Rejected)?;
+        if username == self.username() {
+            return Err(ChatError::Rejected(format!("You are already {}", username)));
+        }
+        self.set_username(&username)
+    }
+
+    /// The name the server accepted, empty until one has been set.
+    pub fn username(&self) -> String {
+        self.session.connection.username()
+    }
+
+    /// The server's address. It changes if the server is found somewhere else while reconnecting.
+    pub fn address(&self) -> String {
+        self.session.connection.address()
+    }
+
+    /// Whether the connection is encrypted.
+    pub fn is_tls(&self) -> bool {
+        self.session.connection.is_tls()
+    }
+
+    // The first name accepted on a connection also starts the session: publish our key, catch up
+    // on what was missed and say hello
+    fn claim(&self, request: Message, password: Option<&str>) -> Result<(), ChatError> {
+        let first = self.username().is_empty();
+        let reply = self.request(&request, &[MessageType::UsernameAvailable, MessageType::UsernameTaken,
+            MessageType::LoginRequired, MessageType::LoginFailed])?;
+        match reply.get_type() {
+            MessageType::UsernameAvailable => {}
+            MessageType::UsernameTaken => return Err(ChatError::Rejected("Username is already taken".to_string())),
+            MessageType::LoginRequired => return Err(ChatError::LoginRequired(reply.get_username())),
+            _ => return Err(ChatError::Rejected(reply.get_message())),
+        }
+        let connection = &self.session.connection;
+        connection.set_username(&reply.get_username());
+        // A guest name needs no password to get back after reconnecting
+        connection.set_password(password.map(str::to_string));
+        if first {
+            connection.send(&connection.public_key_message());
+            let last_seen = *self.session.last_seen.lock().unwrap();
+            match last_seen {
+                Some(after) => catch_up(after, |query| self.fetch_history(query))?,
+                None => {
+                    self.fetch_history(HistoryQuery::default())?;
+                }
+            }
             self.send_message(&Message::builder()
-                .username(&username)
-                .message(&msg)
+                .username(&self.username())
+                .message_type(MessageType::Join)
                 .build());
         }
+        Ok(())
     }
 
-    fn set_username(&mut self) {
-        print!("Enter username: ");
-        io::stdout().flush().expect("Failed to flush stdout");
-        let mut username = String::new();
-        io::stdin()
-            .read_line(&mut username)
-            .unwrap()
-            .to_string();
-        username = username.trim().to_string();
-        if username.is_empty() {
-            error!("Username cannot be empty");
-            self.set_username();
-            return;
-        }
-        if username.contains(" ") {
-            error!("Username cannot contain spaces");
-            self.set_username();
-            return;
-        }
-        if username.len() < 3 || username.len() > 20 {
-            error!("Username cannot be less than 3 or more than 20 characters");
-            self.set_username();
-            return;
-        }
-        if username.to_uppercase() == "SERVER" {
-            error!("Username cannot be SERVER");
-            self.set_username();
-            return;
-        }
-        if self.check_username_availability(&username) {
-            println!("Username set to {}", self.username);
-            return;
-        } else {
-            error!("Username is already taken");
-            self.set_username();
-            return;
-        }
-    }
-
-    fn check_username_availability(&mut self, username: &String) -> bool {
-        self.send_message(
-            &Message::builder()
-                .username(username)
-                .message_type(MessageType::SetUsername)
-                .build()
-        );
-
-        while self.receiver.is_none() {
-            trace!("Waiting for response from server");
-            thread::sleep(std::time::Duration::from_millis(100));
-        }
-        let received_msg = self.receiver.as_ref().unwrap().recv().unwrap();
-
-        match received_msg.get_type() {
-            MessageType::UsernameAvailable => {
-                self.username = received_msg.get_username();
-                true
-            }
-            MessageType::UsernameTaken => {
-                false
-            }
-            _ => {
-                error!("Unexpected message type");
-                false
+    /// Asks the server for stored messages matching `query` and returns them oldest first, once
+    /// all of them have arrived. Ones not seen before are also handed to subscribers.
+    pub fn fetch_history(&self, query: HistoryQuery) -> Result<Vec<Message>, ChatError> {
+        let replies = self.replies.lock().unwrap();
+        *self.session.fetched.lock().unwrap() = Some(Vec::new());
+        let reply = self.exchange(&replies, &Message::builder()
+            .username(&self.username())
+            .message_type(MessageType::FetchMessages)
+            .query(query.clone())
+            .build(), &[MessageType::ClearToSend]);
+        let fetched = self.session.take_fetched(&query);
+        reply?;
+        Ok(fetched)
+    }
+
+    fn request(&self, request: &Message, types: &[MessageType]) -> Result<Message, ChatError> {
+        let replies = self.replies.lock().unwrap();
+        self.exchange(&replies, request, types)
+    }
+
+    fn exchange(&self, replies: &Receiver<Message>, request: &Message, types: &[MessageType]) -> Result<Message, ChatError> {
+        // Anything still queued answered an earlier request that gave up waiting
+        while replies.try_recv().is_ok() {}
+        trace!("Sending {}", request);
+        self.session.connection.send(request);
+        loop {
+            let reply = replies.recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::ConnectionAborted, "Disconnected from server"))?;
+            if types.contains(&reply.get_type()) {
+                return Ok(reply);
             }
+            debug!("Ignoring unexpected reply {}", reply);
+        }
+    }
+
+    fn send_message(&self, message: &Message) {
+        self.session.record(message);
+        self.session.connection.send(message);
+    }
+
+    /// Sends `text` to the current room, the one joined last. The server does not echo messages
+    /// back to their sender, so what was sent is returned, e.g. to show it.
+    pub fn send(&self, text: &str) -> Result<Message, ChatError> {
+        let room = self.current_room()
+            .ok_or_else(|| ChatError::Rejected("You are not in any room, join one first".to_string()))?;
+        Ok(self.send_to(&room, text))
+    }
+
+    /// Sends `text` to `room`, which has to be one of [`rooms`](ChatClient::rooms).
+    pub fn send_to(&self, room: &str, text: &str) -> Message {
+        let message = Message::builder()
+            .username(&self.username())
+            .message(text)
+            .room(room)
+            .build();
+        self.send_message(&message);
+        message
+    }
+
+    /// Describes what you are doing in the current room, like `/me waves`.
+    pub fn action(&self, text: &str) -> Result<Message, ChatError> {
+        let room = self.current_room()
+            .ok_or_else(|| ChatError::Rejected("You are not in any room, join one first".to_string()))?;
+        let message = Message::builder()
+            .username(&self.username())
+            .message(text)
+            .message_type(MessageType::Action)
+            .room(&room)
+            .build();
+        self.send_message(&message);
+        Ok(message)
+    }
+
+    /// Sends a direct message to one online user, encrypted end to end for the key they
+    /// published so the server only ever relays ciphertext. Returns it as it reads before
+    /// encryption.
+    ///
+    /// The first key seen for a user is pinned in `~/.quick_chat/known_keys`. A different one,
+    /// whether here or on a message from them, is refused.
+    pub fn direct_message(&self, recipient: &str, text: &str) -> Result<Message, ChatError> {
+        let user = self.online_user(recipient)?;
+        let recipient = user.username.as_str();
+        let recipient_key = user.public_key.clone()
+            .ok_or_else(|| ChatError::Rejected(format!("{} has not published a key yet", recipient)))?;
+        let username = self.username();
+        let (ciphertext, envelope) = self.session.connection.identity()
+            .seal(text, &username, recipient, &recipient_key)
+            .map_err(ChatError::Rejected)?;
+        let message = Message::builder()
+            .username(&username)
+            .message(&ciphertext)
+            .message_type(MessageType::DirectMessage)
+            .recipient(recipient)
+            .envelope(envelope)
+            .build();
+        let sent = message.decrypted(text);
+        self.session.record(&sent);
+        self.session.connection.send(&message);
+        Ok(sent)
+    }
+
+    /// Fingerprint of your own key, or of the one `username` published, to compare out of band.
+    pub fn fingerprint(&self, username: Option<&str>) -> Result<String, ChatError> {
+        let key = match username {
+            None => self.session.connection.identity().public_key(),
+            Some(username) => self.online_user(username)?.public_key
+                .ok_or_else(|| ChatError::Rejected(format!("{} has not published a key yet", username)))?,
+        };
+        Ok(e2e::fingerprint(&key))
+    }
+
+    /// Creates a room, `#` included, and joins it.
+    pub fn create_room(&self, room: &str) {
+        self.room_request(MessageType::CreateRoom, room);
+    }
+
+    /// Joins a room and makes it the current one.
+    pub fn join_room(&self, room: &str) {
+        self.room_request(MessageType::JoinRoom, room);
+    }
+
+    /// Leaves a room, the previous one becomes current again.
+    pub fn leave_room(&self, room: &str) {
+        self.room_request(MessageType::LeaveRoom, room);
+    }
+
+    /// Asks for the rooms on the server, the answer arrives as an [`Event::Message`].
+    pub fn list_rooms(&self) {
+        self.room_request(MessageType::ListRooms, "");
+    }
+
+    /// Asks who is online and how long they have been idle, the answer arrives as an [`Event::Message`].
+    pub fn who(&self) {
+        self.send_message(&Message::builder()
+            .username(&self.username())
+            .message_type(MessageType::Who)
+            .build());
+    }
+
+    /// Measures the round trip to the server, the pong arrives as an [`Event::Message`].
+    pub fn ping(&self) {
+        let ping = Message::builder()
+            .message_type(MessageType::Ping)
+            .build();
+        *self.session.ping_sent.lock().unwrap() = Some(ping.get_timestamp());
+        self.session.connection.send(&ping);
+    }
+
+    fn room_request(&self, message_type: MessageType, room: &str) {
+        self.send_message(&Message::builder()
+            .username(&self.username())
+            .message_type(message_type)
+            .room(room)
+            .build());
+    }
+
+    /// The room `send` goes to, if any.
+    pub fn current_room(&self) -> Option<String> {
+        self.session.joined_rooms.lock().unwrap().last().cloned()
+    }
+
+    /// Rooms joined, in the order they were joined.
+    pub fn rooms(&self) -> Vec<String> {
+        self.session.joined_rooms.lock().unwrap().clone()
+    }
+
+    /// Who was online according to the latest list from the server.
+    pub fn online_users(&self) -> Vec<Presence> {
+        self.session.online_users.lock().unwrap().clone()
+    }
+
+    // Finds a user however the name was typed, BOB reaches bob
+    fn online_user(&self, username: &str) -> Result<Presence, ChatError> {
+        self.session.online_users.lock().unwrap().iter()
+            .find(|user| usernames::same_user(&user.username, username))
+            .cloned()
+            .ok_or_else(|| ChatError::Rejected(format!("{} is not online", username)))
+    }
+
+    /// The last `count` conversation messages sent or received in `room` during this session,
+    /// direct messages included.
+    pub fn transcript(&self, room: &str, count: usize) -> Vec<Message> {
+        let messages = self.session.messages.lock().unwrap();
+        let mut transcript: Vec<Message> = messages.iter()
+            .rev()
+            .filter(|message| match message.get_type() {
+                MessageType::DirectMessage => true,
+                MessageType::Message | MessageType::Action
+                | MessageType::Join | MessageType::Leave => message.get_room() == room,
+                _ => false,
+            })
+            .take(count)
+            .cloned()
+            .collect();
+        transcript.reverse();
+        transcript
+    }
+
+    /// Disconnects for good. Subscribers get [`Event::Disconnected`].
+    pub fn close(&self) {
+        if !self.session.connection.is_closing() {
+            self.session.connection.close();
         }
     }
+}
 
-    fn fetch_messages(&mut self) {
-        // fetch messages from server
-        self.send_message(
-            &Message::builder()
-                .username(&self.username)
-                .message_type(MessageType::FetchMessages)
-                .build()
-        );
-        // wait for messages to be fetched
-        while self.receiver.is_none() {
-            trace!("Waiting for response from server");
-            thread::sleep(std::time::Duration::from_millis(100));
-        }
-        let received_message = self.receiver.as_ref().unwrap().recv().unwrap();
-
-        if received_message.get_type() == MessageType::ClearToSend {
-            return;
-        } else {
-            error!("Unexpected message type {}", received_message.to_string());
-            exit(1);
-        }
-    }
-
-
-    fn receive_from_server(&mut self) -> JoinHandle<()> {
-        let mut buf = [0u8; 15000];
-        let mut buffer_reader = BufReader::new(
-            self.server_socket.try_clone()
-                .expect("Failed to create client BufReader"));
-
-        trace!("Starting receive_from_server thread");
-        let (sender, receiver) = sync_channel(0);
-        self.receiver = Some(receiver);
-        thread::Builder::new()
-            .name("Message Receving Thread".to_string())
-            .spawn(move || {
-                trace!("Message Receving Thread started");
-                loop {
-                    let result_amt = buffer_reader.read(&mut buf);
-                    let amt = match result_amt {
-                        Ok(amt) => amt,
-                        Err(_) => {
-                            debug!("Socket is closed, exiting now...");
-                            exit(0);
+impl Drop for ChatClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn receive_from_server(session: Arc<Session>, replies: Sender<Message>) {
+    let mut frame_reader = FrameReader::new(session.connection.reader());
+    trace!("Starting receive_from_server thread");
+    thread::Builder::new()
+        .name("Message Receving Thread".to_string())
+        .spawn(move || {
+            trace!("Message Receving Thread started");
+            loop {
+                let frame = match frame_reader.read_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) | Err(_) => {
+                        debug!("Socket is closed");
+                        // Nothing to resume before the server has accepted a username
+                        if session.connection.is_closing() || session.connection.username().is_empty() {
+                            session.emit(Event::Disconnected);
+                            return;
                         }
-                    };
-
-                    let messages = Message::from_bytes(&buf[..amt]);
-                    MESSAGES.lock().unwrap().extend(messages.clone().into_iter());
-                    buf = [0u8; 15000];
-                    for message in messages {
-                        trace!("Received {}", message.to_string());
-                        match message.get_type() {
-                            MessageType::Message | MessageType::Join | MessageType::Leave => {
-                                println!("{}", message.to_string());
-                            }
-                            MessageType::UsernameAvailable | MessageType::UsernameTaken | MessageType::ClearToSend => {
-                                sender.send(message).unwrap();
-                                trace!("Sent message to main thread");
-                            }
-                            _ => {
-                                error!("Unknown message type {}", message.get_type());
+                        match reconnect(&session, &replies) {
+                            Some(resumed) => frame_reader = resumed,
+                            None => {
+                                session.emit(Event::Disconnected);
+                                return;
                             }
                         }
+                        continue;
+                    }
+                };
+
+                session.connection.heard();
+                let messages = match Message::from_bytes(&frame, session.connection.encoding()) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        error!("Dropping malformed frame from server: {}", e);
+                        continue;
                     }
+                };
+                for message in messages {
+                    handle_message(message, &session, &replies);
+                }
+            }
+        }).unwrap();
+}
+
+// Pings the server while connected and hangs up on it once it has gone quiet for too long
+fn start_heartbeat(session: Arc<Session>) {
+    thread::Builder::new()
+        .name("Heartbeat Thread".to_string())
+        .spawn(move || {
+            let connection = &session.connection;
+            while !connection.is_closing() {
+                thread::sleep(HEARTBEAT_INTERVAL);
+                if !connection.is_online() {
+                    continue;
+                }
+                if connection.silent_for() > HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS {
+                    debug!("Server has been silent for {:?}", connection.silent_for());
+                    connection.hang_up();
+                    continue;
                 }
-            }).unwrap()
+                connection.send(&Message::builder()
+                    .message_type(MessageType::Ping)
+                    .build());
+            }
+        }).unwrap();
+}
+
+fn handle_message(message: Message, session: &Session, replies: &Sender<Message>) {
+    trace!("Received {}", message);
+    let connection = &session.connection;
+    let message = match message.get_envelope() {
+        Some(envelope) => {
+            let recipient = message.get_recipient().unwrap_or_default();
+            match connection.identity().open(&message.get_message(), &message.get_username(), &recipient,
+                                             &connection.username(), &envelope) {
+                Ok(text) => message.decrypted(&text),
+                Err(e) => message.decrypted(&format!("[could not decrypt: {}]", e)),
+            }
+        }
+        None => message,
+    };
+    match message.get_type() {
+        MessageType::Message | MessageType::Action | MessageType::DirectMessage
+        | MessageType::Join | MessageType::Leave => {
+            if let Some(fetched) = session.fetched.lock().unwrap().as_mut() {
+                fetched.push(message.clone());
+            }
+            if !session.seen_ids.lock().unwrap().insert(message.get_id()) {
+                trace!("Skipping duplicate message {}", message.get_id());
+                return;
+            }
+            let mut last_seen = session.last_seen.lock().unwrap();
+            if last_seen.is_none_or(|seen| message.get_seq() > seen) {
+                *last_seen = Some(message.get_seq());
+            }
+            drop(last_seen);
+            // Our own comings and goings are never sent to us live, and coming back from history
+            // after a reconnect or fetch they are not news either
+            if matches!(message.get_type(), MessageType::Join | MessageType::Leave)
+                && usernames::same_user(&message.get_username(), &connection.username()) {
+                trace!("Skipping our own {}", message.get_type());
+                return;
+            }
+            session.messages.lock().unwrap().push(message.clone());
+            session.emit(Event::Message(Box::new(message)));
+        }
+        MessageType::JoinRoom => {
+            let mut rooms = session.joined_rooms.lock().unwrap();
+            rooms.retain(|room| *room != message.get_room());
+            rooms.push(message.get_room());
+            drop(rooms);
+            session.emit(Event::Message(Box::new(message)));
+        }
+        MessageType::LeaveRoom => {
+            session.joined_rooms.lock().unwrap().retain(|room| *room != message.get_room());
+            session.emit(Event::Message(Box::new(message)));
+        }
+        MessageType::UserList => {
+            trace!("{} users online", message.get_users().len());
+            *session.online_users.lock().unwrap() = message.get_users();
+            session.emit(Event::Users(message.get_users()));
+        }
+        MessageType::CreateRoom | MessageType::ListRooms | MessageType::Error
+        | MessageType::Who | MessageType::ServerShutdown | MessageType::NickChanged => {
+            session.emit(Event::Message(Box::new(message)));
+        }
+        MessageType::UsernameAvailable | MessageType::UsernameTaken | MessageType::ClearToSend
+        | MessageType::LoginRequired | MessageType::LoginFailed => {
+            if replies.send(message).is_err() {
+                debug!("Nobody is waiting for a reply anymore");
+            }
+        }
+        MessageType::Ping => {
+            connection.send(&Message::builder()
+                .message_type(MessageType::Pong)
+                .timestamp(message.get_timestamp())
+                .build());
+        }
+        MessageType::Pong => {
+            let mut ping_sent = session.ping_sent.lock().unwrap();
+            if *ping_sent == Some(message.get_timestamp()) {
+                *ping_sent = None;
+                drop(ping_sent);
+                session.emit(Event::Message(Box::new(message)));
+            }
+        }
+        MessageType::UnknownMessage => {
+            session.emit(Event::Message(Box::new(message)));
+        }
+        MessageType::Accepted => {
+            session.accept(&message);
+        }
+        // Something only a newer server knows about, nothing to do with it here
+        MessageType::Unknown => {
+            debug!("Ignoring a message of a type this client does not know");
+        }
+        _ => {
+            error!("Unexpected message type {}", message.get_type());
+        }
+    }
+}
+
+// Keeps dialling with exponential backoff until a new session is up, or the client is closed
+fn reconnect(session: &Session, replies: &Sender<Message>) -> Option<FrameReader<Reader>> {
+    let connection = &session.connection;
+    connection.go_offline();
+    session.emit(Event::Reconnecting);
+    let mut delay = INITIAL_BACKOFF;
+    loop {
+        thread::sleep(delay);
+        if connection.is_closing() {
+            return None;
+        }
+        debug!("Reconnecting after {:?}", delay);
+        if let Some(transport) = connection.dial() {
+            match resume(session, transport, replies) {
+                Ok(frame_reader) => return Some(frame_reader),
+                Err(e) => session.emit(Event::ResumeFailed(e)),
+            }
+        }
+        delay = (delay * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Replays the start of a session on a fresh socket: same username, same rooms in the same order,
+// and only the messages missed while offline. Sends keep being held until it is done.
+fn resume(session: &Session, transport: Transport, replies: &Sender<Message>)
+          -> Result<FrameReader<Reader>, String> {
+    let connection = &session.connection;
+    let username = connection.username();
+    let encoding = transport.encoding();
+    let mut frame_reader = FrameReader::new(transport.reader().map_err(|e| e.to_string())?);
+    let mut writer = BufWriter::new(transport.writer().map_err(|e| e.to_string())?);
+    let mut send = |message: &Message| {
+        write_frame(&mut writer, &Message::to_bytes(std::slice::from_ref(message), encoding)).map_err(|e| e.to_string())
+    };
+
+    match connection.password() {
+        Some(password) => send(&Message::builder()
+            .username(&username)
+            .message(&password)
+            .message_type(MessageType::Login)
+            .build())?,
+        None => send(&Message::builder()
+            .username(&username)
+            .message_type(MessageType::SetUsername)
+            .build())?,
+    }
+    let reply = await_reply(&mut frame_reader,
+                            encoding,
+                            &[MessageType::UsernameAvailable, MessageType::UsernameTaken,
+                                MessageType::LoginRequired, MessageType::LoginFailed],
+                            session,
+                            replies)?;
+    match reply.get_type() {
+        MessageType::UsernameAvailable => {}
+        // Most likely the server has not noticed the old connection is gone yet
+        MessageType::UsernameTaken => return Err(format!("{} is still taken", username)),
+        MessageType::LoginRequired => return Err(format!("{} has been registered by someone else", username)),
+        _ => return Err(reply.get_message()),
+    }
+    send(&connection.public_key_message())?;
+
+    let rooms = session.joined_rooms.lock().unwrap().clone();
+    if !rooms.iter().any(|room| room == DEFAULT_ROOM) {
+        send(&Message::builder()
+            .username(&username)
+            .message_type(MessageType::LeaveRoom)
+            .room(DEFAULT_ROOM)
+            .build())?;
+    }
+    for room in &rooms {
+        send(&Message::builder()
+            .username(&username)
+            .message_type(MessageType::JoinRoom)
+            .room(room)
+            .build())?;
+        let reply = await_reply(&mut frame_reader, encoding, &[MessageType::JoinRoom, MessageType::Error],
+                                session, replies)?;
+        // A room the server no longer knows must not stay the one `send` goes to
+        if reply.get_type() == MessageType::Error {
+            session.joined_rooms.lock().unwrap().retain(|joined| joined != room);
+        }
+        handle_message(reply, session, replies);
+    }
+
+    let mut fetch = |query: HistoryQuery| {
+        *session.fetched.lock().unwrap() = Some(Vec::new());
+        let reply = send(&Message::builder()
+            .username(&username)
+            .message_type(MessageType::FetchMessages)
+            .query(query.clone())
+            .build())
+            .and_then(|()| await_reply(&mut frame_reader, encoding, &[MessageType::ClearToSend], session, replies));
+        let fetched = session.take_fetched(&query);
+        reply.map(|_| fetched)
+    };
+    let last_seen = *session.last_seen.lock().unwrap();
+    match last_seen {
+        Some(after) => catch_up(after, fetch)?,
+        None => {
+            fetch(HistoryQuery::default())?;
+        }
     }
+    // Joining the other rooms already announced us there, the default room needs it done by hand
+    if rooms.iter().any(|room| room == DEFAULT_ROOM) {
+        send(&Message::builder()
+            .username(&username)
+            .message_type(MessageType::Join)
+            .build())?;
+    }
+
+    let held = connection.go_online(transport).map_err(|e| e.to_string())?;
+    session.emit(Event::Reconnected { held });
+    Ok(frame_reader)
+}
 
-    fn send_message(&mut self, msg: &Message) {
-        trace!("Sending {}", msg.to_string());
-        MESSAGES.lock().unwrap().push(msg.clone());
-        let msg = msg.clone();
-        let msg_arr = json!([msg]).to_string().into_bytes();
+// Pages forward through everything stored after `after` until the server runs out, a single
+// request stops at CATCH_UP_PAGE messages
+fn catch_up<E>(mut after: u64, mut fetch: impl FnMut(HistoryQuery) -> Result<Vec<Message>, E>) -> Result<(), E> {
+    loop {
+        let page = fetch(HistoryQuery {
+            after: Some(after),
+            limit: Some(CATCH_UP_PAGE),
+            ..HistoryQuery::default()
+        })?;
+        // Comes back oldest first and cut to the limit, a full page means there may be more
+        match page.get(CATCH_UP_PAGE - 1) {
+            Some(last) => after = last.get_seq(),
+            None => return Ok(()),
+        }
+    }
+}
 
-        let _ = match self.buffer_writer
-            .write(&msg_arr)
-            .and_then(|_| self.buffer_writer.flush()) {
-            Ok(_) => {}
+// Reads until one of `types` arrives, handling everything else as usual on the way
+fn await_reply(frame_reader: &mut FrameReader<Reader>,
+               encoding: Encoding,
+               types: &[MessageType],
+               session: &Session,
+               replies: &Sender<Message>) -> Result<Message, String> {
+    loop {
+        let frame = frame_reader.read_frame()
+            .map_err(|e| e.to_string())?
+            .ok_or("Connection closed")?;
+        session.connection.heard();
+        let messages = match Message::from_bytes(&frame, encoding) {
+            Ok(messages) => messages,
             Err(e) => {
-                error!("Failed to flush buffer: {}", e);
+                error!("Dropping malformed frame from server: {}", e);
+                continue;
             }
         };
+        let mut reply = None;
+        for message in messages {
+            if reply.is_none() && types.contains(&message.get_type()) {
+                reply = Some(message);
+            } else {
+                handle_message(message, session, replies);
+            }
+        }
+        if let Some(reply) = reply {
+            return Ok(reply);
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,200 +1,752 @@
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
 
-use lazy_static::lazy_static;
-use log::{debug, error, trace};
-use serde_json::json;
+use log::{debug, error, info, trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{Instant, interval_at};
 
-use crate::message::Message;
+use crate::accounts;
+use crate::accounts::MIN_PASSWORD_LEN;
+use crate::encoding::Encoding;
+use crate::frame::{AsyncFrameReader, write_frame_async};
+use crate::history::HistoryQuery;
+use crate::hooks::ClientInfo;
+use crate::message::{DEFAULT_ROOM, Message, now_nanos};
 use crate::message_types::MessageType;
-use crate::server;
+use crate::outbound::{OutboundQueue, SharedFrame};
+use crate::presence::Presence;
+use crate::server::ServerState;
+use crate::usernames;
 
+// Handlers never touch sockets directly. Everything for a client goes onto its outbound queue,
+// which a separate writer task drains, so a slow peer can't hold up the sender.
+#[derive(Clone)]
 pub struct ClientHandler {
-    buffer_reader: BufReader<TcpStream>,
-    buffer_writer: BufWriter<TcpStream>,
+    state: Arc<ServerState>,
+    outbound: Arc<OutboundQueue>,
+    broadcasts: UnboundedSender<Broadcast>,
     username: String,
+    public_key: Option<String>,
+    rooms: HashSet<String>,
+    connected_at: i64,
+    // Shared with every clone so the copy in the server's client list sees activity too
+    last_active: Arc<AtomicI64>,
+    address: SocketAddr,
+    tls: bool,
+    // Settled on in the handshake, for both directions
+    encoding: Encoding,
     pub(crate) client_name: String,
 }
 
-pub(crate) type Messages = Arc<Mutex<Vec<Message>>>;
+// Either half of a plain socket or of a TLS stream
+pub(crate) type ReadHalf = Box<dyn AsyncRead + Unpin + Send>;
+pub(crate) type WriteHalf = Box<dyn AsyncWrite + Unpin + Send>;
 
-lazy_static! {
-    static ref MESSAGES: Messages = Arc::new(Mutex::new(Vec::new()));
-}
+const PAGE_SIZE: usize = 50;
+// Both ends ping each other this often and give up on a peer that has been silent for
+// MAX_MISSED_HEARTBEATS pings in a row
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+pub(crate) const MAX_MISSED_HEARTBEATS: u32 = 3;
+// Slows down password guessing without holding up anyone else
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
 
 impl ClientHandler {
-    pub fn new(client_socket: TcpStream) -> ClientHandler {
+    pub(crate) fn new(address: SocketAddr,
+                      tls: bool,
+                      encoding: Encoding,
+                      outbound: Arc<OutboundQueue>,
+                      broadcasts: UnboundedSender<Broadcast>,
+                      state: Arc<ServerState>) -> ClientHandler {
         ClientHandler {
-            buffer_reader: BufReader::new(
-                client_socket
-                    .try_clone()
-                    .expect("Failed to create client BufReader")),
+            state,
+
+            outbound,
 
-            buffer_writer: BufWriter::new(
-                client_socket
-                    .try_clone()
-                    .expect("Failed to create client BufWriter")),
+            broadcasts,
 
             username: String::new(),
 
-            client_name: client_socket.peer_addr().unwrap().to_string(),
+            public_key: None,
+
+            rooms: HashSet::from([DEFAULT_ROOM.to_string()]),
+
+            connected_at: now_nanos(),
+
+            last_active: Arc::new(AtomicI64::new(now_nanos())),
+
+            address,
+
+            tls,
+
+            encoding,
+
+            client_name: address.to_string(),
         }
     }
 
-    pub unsafe fn run(mut self) {
-        let mut buf = [0u8; 15000];
+    pub(crate) async fn run(mut self, mut frame_reader: AsyncFrameReader<ReadHalf>) {
+        let mut heartbeat = interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        let mut missed = 0;
         loop {
-            let result_amt = self.buffer_reader.read(&mut buf);
-            let amt = match result_amt {
-                Ok(amt) => amt,
-                Err(_) => {
-                    debug!("Failed to read from client. Probably disconnected.");
+            let read = tokio::select! {
+                read = frame_reader.read_frame() => read,
+                _ = heartbeat.tick() => {
+                    if missed == MAX_MISSED_HEARTBEATS {
+                        debug!("{} missed {} heartbeats, dropping it", self.client_name, missed);
+                        break;
+                    }
+                    missed += 1;
+                    self.send_to_client(&Message::builder()
+                        .message_type(MessageType::Ping)
+                        .build());
+                    continue;
+                }
+                _ = self.outbound.closed() => {
+                    debug!("Outbound queue for {} closed, dropping the connection", self.client_name);
                     break;
                 }
             };
-            if buf[..amt].is_empty() {
-                debug!("Client {} disconnected.", self.client_name);
-                break;
-            }
-            debug!("Received {} bytes from {}", amt, self.client_name);
-            let messages = Message::from_bytes(&buf[..amt]);
+            let frame = match read {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    debug!("Client {} disconnected.", self.client_name);
+                    break;
+                }
+                Err(e) => {
+                    debug!("Failed to read from client. Probably disconnected. {}", e);
+                    break;
+                }
+            };
+            // Anything at all from the client shows it is still there
+            missed = 0;
+            debug!("Received {} bytes from {}", frame.len(), self.client_name);
+            let messages = match Message::from_bytes(&frame, self.encoding) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    error!("Dropping malformed frame from {}: {}", self.client_name, e);
+                    continue;
+                }
+            };
             debug!("Received {} messages", messages.len());
-            for message in messages {
-                trace!("Received {}", message.to_string());
+            for mut message in messages {
+                trace!("Received {}", message);
+                if !allowed_before_login(message.get_type()) {
+                    if self.username.is_empty() {
+                        self.send_error("Pick a username or log in first");
+                        continue;
+                    }
+                    // Whatever name the client put in, it only ever speaks as the one it was given
+                    message.set_username(&self.username);
+                }
                 match message.get_type() {
-                    MessageType::Message | MessageType::Join | MessageType::Leave => {
-                        self.send_to_other_clients(&message);
+                    MessageType::Message | MessageType::Action | MessageType::DirectMessage => {
+                        self.last_active.store(now_nanos(), Ordering::Relaxed);
+                        if !self.passes_hooks(&mut message) {
+                            self.send_error("Your message was not delivered");
+                            continue;
+                        }
                     }
-                    MessageType::SetUsername => {
-                        let username = message.get_username().to_string();
-                        if self.is_username_available(username.to_string()) {
-                            self.set_username(&username);
-                            trace!("Username set to {}", self.username);
-                            self.send_to_client({
-                                &Message::builder()
-                                    .username(&username)
-                                    .message_type(MessageType::UsernameAvailable)
-                                    .build()
-                            }
-                            );
+                    _ => {}
+                }
+                match message.get_type() {
+                    MessageType::Message | MessageType::Action | MessageType::Join | MessageType::Leave => {
+                        if self.rooms.contains(&message.get_room()) {
+                            let stored = self.send_to_other_clients(&message);
+                            self.acknowledge(&message, &stored);
                         } else {
-                            trace!("Username {} is not available", username);
-                            self.send_to_client(&Message::builder()
-                                .username(&self.username)
-                                .message_type(MessageType::UsernameTaken)
-                                .build());
+                            self.send_error(&format!("You are not in {}", message.get_room()));
                         }
                     }
+                    MessageType::DirectMessage => {
+                        self.send_direct_message(&message);
+                    }
+                    MessageType::CreateRoom => {
+                        self.create_room(&message.get_room());
+                    }
+                    MessageType::JoinRoom => {
+                        self.join_room(&message.get_room());
+                    }
+                    MessageType::LeaveRoom => {
+                        self.leave_room(&message.get_room());
+                    }
+                    MessageType::ListRooms => {
+                        self.list_rooms();
+                    }
+                    MessageType::Who => {
+                        let users = presence(self.state.clients.lock().unwrap().iter());
+                        self.send_to_client(&Message::builder()
+                            .message_type(MessageType::Who)
+                            .users(users)
+                            .build());
+                    }
+                    MessageType::SetUsername => {
+                        self.claim_username(&message.get_username());
+                    }
+                    MessageType::Login => {
+                        self.login(&message.get_username(), &message.get_message()).await;
+                    }
+                    MessageType::Register => {
+                        self.register(&message.get_username(), &message.get_message()).await;
+                    }
                     MessageType::FetchMessages => {
-                        self.sync_messages();
+                        self.sync_messages(&message.get_query());
+                    }
+                    MessageType::PublicKey => {
+                        self.set_public_key(&message.get_message());
+                        self.broadcast_user_list();
+                    }
+                    MessageType::Ping => {
+                        self.send_to_client(&Message::builder()
+                            .message_type(MessageType::Pong)
+                            .timestamp(message.get_timestamp())
+                            .build());
+                    }
+                    MessageType::Pong => {
+                        trace!("Pong from {}", self.client_name);
+                    }
+                    // Sent by a newer client, which can tell from the echoed timestamp what was not understood
+                    MessageType::Unknown => {
+                        debug!("{} sent a message of a type this server does not know", self.client_name);
+                        self.send_to_client(&Message::builder()
+                            .message_type(MessageType::UnknownMessage)
+                            .timestamp(message.get_timestamp())
+                            .build());
                     }
                     _ => {
-                        error!("Unknown message type {}", message.get_type());
+                        error!("Unexpected message type {} from {}", message.get_type(), self.client_name);
                     }
                 }
             }
-            buf = [0u8; 15000];
+        }
+        // A connection that never picked a name was never announced anywhere
+        if !self.username.is_empty() {
+            for room in self.rooms.clone() {
+                self.send_to_other_clients(&Message::builder()
+                    .username(&self.username)
+                    .message_type(MessageType::Leave)
+                    .room(&room)
+                    .build());
+            }
+        }
+        let client = self.info();
+        for hook in &self.state.hooks {
+            hook.on_leave(&client);
+        }
+        self.state.remove_client(&self.client_name);
+        self.broadcast_user_list();
+        self.outbound.close();
+    }
+
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            address: self.address,
+            username: self.username.clone(),
+            tls: self.tls,
+        }
+    }
+
+    // Every hook gets a say, the first one to refuse drops the message
+    fn passes_hooks(&self, message: &mut Message) -> bool {
+        if self.state.hooks.is_empty() {
+            return true;
+        }
+        let client = self.info();
+        self.state.hooks.iter().all(|hook| hook.on_message(&client, message))
+    }
+
+    fn send_to_client(&self, message: &Message) {
+        trace!("Sending {}", message);
+        self.write_messages(std::slice::from_ref(message));
+    }
+
+    // Queues one frame worth of messages, returns false once the client has been dropped
+    fn write_messages(&self, messages: &[Message]) -> bool {
+        self.outbound.try_push(SharedFrame::new(messages.to_vec()))
+    }
+
+    fn broadcast(&self, message: Message, audience: Audience) {
+        let broadcast = Broadcast {
+            message,
+            audience,
+            skip: Some(self.client_name.clone()),
+        };
+        if self.broadcasts.send(broadcast).is_err() {
+            error!("Broadcast task has stopped, dropping message");
+        }
+    }
+
+    fn record(&self, message: &Message) -> Message {
+        let mut message = message.clone();
+        // Stamp and store under the same lock so sequence numbers follow history order
+        let mut history = self.state.history.lock().unwrap();
+        message.stamp(history.last_seq() + 1);
+        trace!("Stamped message {} with seq {}", message.get_id(), message.get_seq());
+        if let Err(e) = history.append(&message) {
+            error!("Failed to store message in history: {}", e);
+        }
+        message
+    }
+
+    // Returns the message as it was stored
+    fn send_to_other_clients(&self, message: &Message) -> Message {
+        let message = self.record(message);
+        let room = message.get_room();
+        self.broadcast(message.clone(), Audience::Room(room));
+        message
+    }
+
+    // Lets the sender match its own copy to the stored one, so it is not shown again when it comes
+    // back in a history fetch
+    fn acknowledge(&self, sent: &Message, stored: &Message) {
+        let mut accepted = Message::builder()
+            .message_type(MessageType::Accepted)
+            .timestamp(sent.get_timestamp())
+            .build();
+        accepted.restore_id(stored.get_id(), stored.get_seq());
+        self.send_to_client(&accepted);
+    }
+
+    fn send_direct_message(&self, message: &Message) {
+        let recipient = message.get_recipient().unwrap_or_default();
+        if message.get_envelope().is_none() {
+            self.send_error("Direct messages have to be end-to-end encrypted");
+            return;
+        }
+        let online_name = match self.online_name(&recipient) {
+            Some(online_name) => online_name,
+            None => {
+                self.send_error(&format!("{} is not online", recipient));
+                return;
+            }
+        };
+        // Stored under the name they are online as, history only shows it to that exact name
+        let mut message = message.clone();
+        message.set_recipient(&online_name);
+        let stored = self.record(&message);
+        self.acknowledge(&message, &stored);
+        self.broadcast(stored, Audience::User(online_name));
+    }
+
+    fn send_error(&self, error: &str) {
+        self.send_to_client(&Message::builder()
+            .message(error)
+            .message_type(MessageType::Error)
+            .build());
+    }
+
+    fn sync_messages(&self, query: &HistoryQuery) {
+        debug!("Syncing messages with {}", self.client_name);
+        self.send_history(query);
+        self.send_to_client(&Message::builder()
+            .message_type(MessageType::ClearToSend)
+            .build());
+    }
+
+    fn send_history(&self, query: &HistoryQuery) {
+        let messages = self.state.history.lock().unwrap()
+            .query(query, &|message| is_visible_to(message, &self.username, &self.rooms));
+        debug!("Sending {} messages to {}", messages.len(), self.client_name);
+        for page in messages.chunks(PAGE_SIZE) {
+            if !self.write_messages(page) {
+                return;
+            }
+            trace!("Sent page of {} messages to {}", page.len(), self.client_name);
+        }
+        trace!("Sent {} messages to {}", messages.len(), self.client_name);
+    }
+
+    fn create_room(&mut self, room: &str) {
+        if !is_valid_room_name(room) {
+            self.send_error(&format!("{} is not a valid room name", room));
+            return;
+        }
+        if !self.state.rooms.lock().unwrap().insert(room.to_string()) {
+            self.send_error(&format!("{} already exists", room));
+            return;
+        }
+        debug!("{} created room {}", self.username, room);
+        self.send_to_client(&Message::builder()
+            .message_type(MessageType::CreateRoom)
+            .room(room)
+            .build());
+        self.join_room(room);
+    }
+
+    fn join_room(&mut self, room: &str) {
+        if !self.state.rooms.lock().unwrap().contains(room) {
+            self.send_error(&format!("{} does not exist", room));
+            return;
+        }
+        if !self.rooms.contains(room) {
+            self.rooms.insert(room.to_string());
+            self.sync_rooms();
+            self.send_history(&HistoryQuery {
+                room: Some(room.to_string()),
+                ..HistoryQuery::default()
+            });
+            self.send_to_other_clients(&Message::builder()
+                .username(&self.username)
+                .message_type(MessageType::Join)
+                .room(room)
+                .build());
+        }
+        self.send_to_client(&Message::builder()
+            .message_type(MessageType::JoinRoom)
+            .room(room)
+            .build());
+    }
+
+    fn leave_room(&mut self, room: &str) {
+        if !self.rooms.contains(room) {
+            self.send_error(&format!("You are not in {}", room));
+            return;
         }
         self.send_to_other_clients(&Message::builder()
             .username(&self.username)
             .message_type(MessageType::Leave)
+            .room(room)
+            .build());
+        self.rooms.remove(room);
+        self.sync_rooms();
+        self.send_to_client(&Message::builder()
+            .message_type(MessageType::LeaveRoom)
+            .room(room)
             .build());
-        server::remove_client(&self.client_name);
-        drop(self);
     }
 
-    fn send_to_client(&mut self, message: &Message) {
-        trace!("Sending {}", message.to_string());
-        let msg = message.clone();
-        let msg_arr = json!([msg]).to_string().into_bytes();
+    fn list_rooms(&self) {
+        let rooms = self.state.rooms.lock().unwrap()
+            .iter()
+            .map(|room| {
+                if self.rooms.contains(room) {
+                    format!("{} (joined)", room)
+                } else {
+                    room.clone()
+                }
+            })
+            .collect();
+        self.send_to_client(&Message::builder()
+            .rooms(rooms)
+            .message_type(MessageType::ListRooms)
+            .build());
+    }
 
-        let _ = match self.buffer_writer
-            .write(&msg_arr)
-            .and_then(|_| self.buffer_writer.flush()) {
-            Ok(_) => {}
+    // Mirror this handler's room membership onto its copy in the client list, which is what fan-out reads
+    fn sync_rooms(&self) {
+        for client in self.state.clients.lock().unwrap().iter_mut() {
+            if self == client {
+                client.rooms = self.rooms.clone();
+            }
+        }
+    }
+
+    // Guests can take any free name that nobody has registered
+    fn claim_username(&mut self, username: &str) {
+        let username = match usernames::validate(username) {
+            Ok(username) => username,
             Err(e) => {
-                error!("Failed to flush {}'s buffer: {}", self.client_name, e);
+                self.send_login_failed(&e);
+                return;
             }
         };
+        let (registered_name, guests_allowed) = {
+            let accounts = self.state.accounts.lock().unwrap();
+            (accounts.registered_name(&username), accounts.guests_allowed())
+        };
+        if let Some(registered_name) = registered_name {
+            self.send_to_client(&Message::builder()
+                .username(&registered_name)
+                .message_type(MessageType::LoginRequired)
+                .build());
+            return;
+        }
+        if !guests_allowed {
+            self.send_login_failed("This server only admits registered users, connect with --register to create an account");
+            return;
+        }
+        if self.is_username_available(&username) {
+            self.accept_username(&username);
+        } else {
+            self.send_username_taken(&username);
+        }
     }
 
-    fn send_to_other_clients(&mut self, message: &Message) {
-        MESSAGES.lock().unwrap().push(message.clone());
-        for client in server::CLIENT_HANDLERS.lock().unwrap().iter_mut() {
-            if self == client {
-                trace!("Skipped sending message to {}", client.client_name);
-                continue;
+    async fn login(&mut self, username: &str, password: &str) {
+        let account = {
+            let accounts = self.state.accounts.lock().unwrap();
+            accounts.registered_name(username).zip(accounts.password_hash(username))
+        };
+        let (username, verified) = match account {
+            Some((registered_name, password_hash)) => {
+                let password = password.to_string();
+                let verified = tokio::task::spawn_blocking(move || accounts::verify_password(&password, &password_hash))
+                    .await
+                    .unwrap_or(false);
+                (registered_name, verified)
             }
-            trace!("Sending message to client: {}", client.client_name);
-            client.send_to_client(&message);
+            None => (username.to_string(), false),
+        };
+        if !verified {
+            warn!("Failed login as {} from {}", username, self.client_name);
+            tokio::time::sleep(LOGIN_FAILURE_DELAY).await;
+            self.send_login_failed("Wrong username or password");
+            return;
+        }
+        if self.is_username_available(&username) {
+            debug!("{} logged in as {}", self.client_name, username);
+            self.accept_username(&username);
+        } else {
+            self.send_username_taken(&username);
         }
     }
 
-    fn sync_messages(&mut self) {
-        debug!("Syncing messages with {}", self.client_name);
-        let messages = MESSAGES.lock().unwrap().clone();
-        // json array of messages
-        let msg_arr = json!(messages.to_vec());
-        debug!("Sending {} messages to {}", messages.len(), self.client_name);
-        trace!("Sending {}", msg_arr.to_string());
-        let _ = match self.buffer_writer
-            .write(msg_arr.to_string().as_bytes())
-            .and_then(|_| self.buffer_writer.flush()) {
-            Ok(_) => {
-                debug!("Sent {} messages to {}", messages.len(), self.client_name);
+    async fn register(&mut self, username: &str, password: &str) {
+        let username = match usernames::validate(username) {
+            Ok(username) => username,
+            Err(e) => {
+                self.send_login_failed(&e);
+                return;
             }
+        };
+        let username = username.as_str();
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            self.send_login_failed(&format!("Passwords need at least {} characters", MIN_PASSWORD_LEN));
+            return;
+        }
+        if self.state.accounts.lock().unwrap().is_registered(username) {
+            self.send_login_failed(&format!("{} is already registered", username));
+            return;
+        }
+        // A guest using the name right now has to leave before it can be registered
+        if !self.is_username_available(username) {
+            self.send_username_taken(username);
+            return;
+        }
+        let password = password.to_string();
+        let hashed = tokio::task::spawn_blocking(move || accounts::hash_password(&password)).await;
+        let password_hash = match hashed.map_err(|e| e.to_string()).and_then(|hashed| hashed) {
+            Ok(password_hash) => password_hash,
             Err(e) => {
-                error!("Failed to flush {}'s buffer: {}", self.client_name, e);
+                error!("Failed to hash password: {}", e);
+                self.send_login_failed("Could not register right now, try again later");
+                return;
             }
         };
+        let added = {
+            let mut accounts = self.state.accounts.lock().unwrap();
+            if accounts.is_registered(username) {
+                Err(format!("{} is already registered", username))
+            } else {
+                accounts.add(username, password_hash).map_err(|e| {
+                    error!("Failed to store account {}: {}", username, e);
+                    "Could not register right now, try again later".to_string()
+                })
+            }
+        };
+        match added {
+            Ok(()) => {
+                info!("Registered account {}", username);
+                self.accept_username(username);
+            }
+            Err(e) => self.send_login_failed(&e),
+        }
+    }
 
-        trace!("Synced messages {} with {}", messages.len(), self.client_name);
+    // Also how a connected user changes name, everyone named gets told about the rename
+    fn accept_username(&mut self, username: &str) {
+        let old_username = self.username.clone();
+        if !self.take_username(username) {
+            self.send_username_taken(username);
+            return;
+        }
+        trace!("Username set to {}", self.username);
         self.send_to_client(&Message::builder()
-            .message_type(MessageType::ClearToSend)
+            .username(username)
+            .message_type(MessageType::UsernameAvailable)
+            .build());
+        if !old_username.is_empty() && old_username != username {
+            debug!("{} is now known as {}", old_username, username);
+            let broadcast = Broadcast {
+                message: Message::builder()
+                    .username(username)
+                    .message(&old_username)
+                    .message_type(MessageType::NickChanged)
+                    .build(),
+                audience: Audience::Named,
+                skip: None,
+            };
+            if self.broadcasts.send(broadcast).is_err() {
+                error!("Broadcast task has stopped, dropping nick change");
+            }
+        }
+        self.broadcast_user_list();
+    }
+
+    fn send_username_taken(&self, username: &str) {
+        trace!("Username {} is not available", username);
+        self.send_to_client(&Message::builder()
+            .username(&self.username)
+            .message_type(MessageType::UsernameTaken)
+            .build());
+    }
+
+    fn send_login_failed(&self, reason: &str) {
+        self.send_to_client(&Message::builder()
+            .message(reason)
+            .message_type(MessageType::LoginFailed)
             .build());
     }
 
-    fn is_username_available(&self, username: String) -> bool {
-        for client in server::CLIENT_HANDLERS.lock().unwrap().iter() {
-            trace!("Checking username {} against {}", username, client.username);
-            trace!("{}", client);
-            if client.username.eq(&username) {
-                trace!("Username {} is not available", username);
-                return false;
+    fn is_username_available(&self, username: &str) -> bool {
+        match self.online_name(username) {
+            // Names are unique, so one that matches ours is ours and may be respelled
+            Some(online_name) if usernames::same_user(&online_name, &self.username) => true,
+            Some(online_name) => {
+                trace!("Username {} is not available, {} is online", username, online_name);
+                false
+            }
+            None => {
+                trace!("Username {} is available", username);
+                true
+            }
+        }
+    }
+
+    // How a connected user who goes by `username`, or something that looks like it, is really called
+    fn online_name(&self, username: &str) -> Option<String> {
+        let canonical = usernames::canonical(username);
+        self.state.clients.lock().unwrap().iter()
+            .filter(|client| !client.username.is_empty())
+            .find(|client| usernames::canonical(&client.username) == canonical)
+            .map(|client| client.username.clone())
+    }
+
+    // Checks the name is free and claims it under one lock, so two clients can't both end up with it
+    fn take_username(&mut self, username: &str) -> bool {
+        let canonical = usernames::canonical(username);
+        let mut clients = self.state.clients.lock().unwrap();
+        let taken = clients.iter()
+            .any(|client| client != self && !client.username.is_empty() && usernames::canonical(&client.username) == canonical);
+        if taken {
+            return false;
+        }
+        self.username = username.to_string();
+        for client in clients.iter_mut() {
+            if self == client {
+                client.username = username.to_string();
             }
         }
-        trace!("Username {} is available", username);
         true
     }
 
-    fn set_username(&mut self, username: &String) {
-        self.username = username.clone();
-        for client in server::CLIENT_HANDLERS.lock().unwrap().iter_mut() {
+    fn set_public_key(&mut self, public_key: &str) {
+        self.public_key = Some(public_key.to_string());
+        for client in self.state.clients.lock().unwrap().iter_mut() {
             if self == client {
-                client.username = username.clone();
+                client.public_key = self.public_key.clone();
             }
         }
     }
-}
 
-impl std::fmt::Display for ClientHandler {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Client: {}\t Username: {}", self.client_name, self.username)
+    // Pushes the current user list to everyone who has picked a username
+    fn broadcast_user_list(&self) {
+        let users = presence(self.state.clients.lock().unwrap().iter());
+        let broadcast = Broadcast {
+            message: Message::builder()
+                .message_type(MessageType::UserList)
+                .users(users)
+                .build(),
+            audience: Audience::Named,
+            skip: None,
+        };
+        if self.broadcasts.send(broadcast).is_err() {
+            error!("Broadcast task has stopped, dropping user list");
+        }
+    }
+
+    pub(crate) fn accepts(&self, broadcast: &Broadcast) -> bool {
+        if broadcast.skip.as_ref() == Some(&self.client_name) {
+            return false;
+        }
+        match &broadcast.audience {
+            Audience::Room(room) => self.rooms.contains(room),
+            Audience::User(username) => self.username == *username,
+            Audience::Named => !self.username.is_empty(),
+        }
+    }
+
+    pub(crate) fn outbound(&self) -> Arc<OutboundQueue> {
+        self.outbound.clone()
     }
 }
 
-impl Clone for ClientHandler {
-    fn clone(&self) -> Self {
-        Self {
-            buffer_reader: BufReader::new(
-                self.buffer_reader.get_ref().try_clone().expect("Failed to create client BufReader")),
-            buffer_writer: BufWriter::new(
-                self.buffer_writer.get_ref().try_clone().expect("Failed to create client BufWriter")),
-            username: self.username.clone(),
-            client_name: self.client_name.clone(),
+pub(crate) enum Audience {
+    Room(String),
+    User(String),
+    // Everyone who has picked a username
+    Named,
+}
+
+pub(crate) struct Broadcast {
+    pub(crate) message: Message,
+    pub(crate) audience: Audience,
+    // client_name of the sender, who already has its own copy
+    pub(crate) skip: Option<String>,
+}
+
+// Drains a client's outbound queue onto its socket until the queue closes or the socket fails
+pub(crate) async fn write_outbound(mut writer: WriteHalf, encoding: Encoding, queue: Arc<OutboundQueue>, client_name: String) {
+    while let Some(frame) = queue.pop().await {
+        if let Err(e) = write_frame_async(&mut writer, frame.bytes(encoding)).await {
+            error!("Failed to flush {}'s buffer: {}", client_name, e);
+            queue.close();
+            break;
         }
     }
+    trace!("Writer for {} stopped", client_name);
+}
+
+fn presence<'a>(client_handlers: impl IntoIterator<Item=&'a ClientHandler>) -> Vec<Presence> {
+    let now = now_nanos();
+    client_handlers.into_iter()
+        .filter(|client| !client.username.is_empty())
+        .map(|client| Presence {
+            username: client.username.clone(),
+            connected_at: client.connected_at,
+            idle_secs: ((now - client.last_active.load(Ordering::Relaxed)) / 1_000_000_000).max(0) as u64,
+            public_key: client.public_key.clone(),
+        })
+        .collect()
+}
+
+// Everything else needs a username to be sent under
+fn allowed_before_login(message_type: MessageType) -> bool {
+    matches!(message_type, MessageType::SetUsername | MessageType::Login | MessageType::Register
+        | MessageType::Ping | MessageType::Pong | MessageType::Unknown)
+}
+
+// Direct messages only show up in the history of the two people involved
+fn is_visible_to(message: &Message, username: &str, rooms: &HashSet<String>) -> bool {
+    match message.get_recipient() {
+        Some(recipient) => recipient == username || message.get_username() == username,
+        None => rooms.contains(&message.get_room()),
+    }
+}
+
+fn is_valid_room_name(room: &str) -> bool {
+    room.starts_with('#')
+        && (2..=32).contains(&room.chars().count())
+        && !room.chars().any(char::is_whitespace)
+}
+
+impl std::fmt::Display for ClientHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Client: {}\t Username: {}", self.client_name, self.username)
+    }
 }
 
 impl PartialEq<Self> for ClientHandler {
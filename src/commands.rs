@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+
+use quick_chat::{ChatClient, Message};
+
+pub(crate) type CommandHandler = fn(&mut dyn Console, &[String]) -> Result<(), String>;
+
+// What commands need from a front end, so the plain terminal and the full-screen one share them
+pub(crate) trait Console {
+    fn client(&self) -> &ChatClient;
+    fn commands(&self) -> &CommandRegistry;
+    // Shows command output, one entry per line
+    fn print(&mut self, text: &str);
+    // Shows a message this user just sent, which the server does not send back
+    fn echo(&mut self, message: &Message);
+    fn clear(&mut self);
+    fn quit(&mut self);
+}
+
+pub(crate) struct Command {
+    pub(crate) name: &'static str,
+    pub(crate) usage: &'static str,
+    pub(crate) help: &'static str,
+    pub(crate) min_args: usize,
+    // The last argument swallows the rest of the line, so `/msg bob hi there` has two arguments
+    pub(crate) max_args: usize,
+    pub(crate) handler: CommandHandler,
+}
+
+impl Command {
+    fn usage_line(&self) -> String {
+        if self.usage.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {}", self.name, self.usage)
+        }
+    }
+}
+
+pub(crate) struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    pub(crate) fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn register(&mut self, command: Command) {
+        self.commands.insert(command.name, command);
+    }
+
+    // Takes the line without its leading slash and returns the handler along with validated arguments
+    pub(crate) fn parse(&self, line: &str) -> Result<(CommandHandler, Vec<String>), String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let command = self.commands.get(name)
+            .ok_or_else(|| format!("Unknown command /{}, try /help", name))?;
+        let args = split_args(rest.trim(), command.max_args);
+        if args.len() < command.min_args || args.len() > command.max_args {
+            return Err(format!("Usage: {}", command.usage_line()));
+        }
+        Ok((command.handler, args))
+    }
+
+    pub(crate) fn help(&self, name: Option<&str>) -> Result<String, String> {
+        match name {
+            Some(name) => {
+                let command = self.commands.get(name.trim_start_matches('/'))
+                    .ok_or_else(|| format!("Unknown command /{}", name))?;
+                Ok(format!("{} - {}", command.usage_line(), command.help))
+            }
+            None => Ok(self.commands.values()
+                .map(|command| format!("{:<24} {}", command.usage_line(), command.help))
+                .collect::<Vec<String>>()
+                .join("\n")),
+        }
+    }
+}
+
+fn split_args(mut rest: &str, max_args: usize) -> Vec<String> {
+    let mut args = Vec::new();
+    while !rest.is_empty() {
+        if args.len() + 1 == max_args {
+            args.push(rest.to_string());
+            break;
+        }
+        let (arg, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        args.push(arg.to_string());
+        rest = remainder.trim_start();
+    }
+    args
+}
+
+pub(crate) fn register_builtin(registry: &mut CommandRegistry) {
+    registry.register(Command {
+        name: "help",
+        usage: "[command]",
+        help: "List commands, or show how to use one",
+        min_args: 0,
+        max_args: 1,
+        handler: help,
+    });
+    registry.register(Command {
+        name: "quit",
+        usage: "",
+        help: "Disconnect and exit",
+        min_args: 0,
+        max_args: 0,
+        handler: |console, _| {
+            console.quit();
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "me",
+        usage: "<action>",
+        help: "Describe what you are doing, e.g. /me waves",
+        min_args: 1,
+        max_args: 1,
+        handler: me,
+    });
+    registry.register(Command {
+        name: "msg",
+        usage: "<user> <text>",
+        help: "Send an end-to-end encrypted direct message to one user",
+        min_args: 2,
+        max_args: 2,
+        handler: msg,
+    });
+    registry.register(Command {
+        name: "fingerprint",
+        usage: "[user]",
+        help: "Show your key fingerprint, or a user's, to compare out of band",
+        min_args: 0,
+        max_args: 1,
+        handler: fingerprint,
+    });
+    registry.register(Command {
+        name: "nick",
+        usage: "<name>",
+        help: "Change your username",
+        min_args: 1,
+        max_args: 1,
+        handler: |console, args| console.client().change_username(&args[0]).map_err(|e| e.to_string()),
+    });
+    registry.register(Command {
+        name: "who",
+        usage: "",
+        help: "List who is online and how long they have been idle",
+        min_args: 0,
+        max_args: 0,
+        handler: |console, _| {
+            console.client().who();
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "ping",
+        usage: "",
+        help: "Measure the round trip to the server",
+        min_args: 0,
+        max_args: 0,
+        handler: |console, _| {
+            console.client().ping();
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "history",
+        usage: "[count]",
+        help: "Show the last messages in the current room, 10 by default",
+        min_args: 0,
+        max_args: 1,
+        handler: history,
+    });
+    registry.register(Command {
+        name: "clear",
+        usage: "",
+        help: "Clear the screen",
+        min_args: 0,
+        max_args: 0,
+        handler: |console, _| {
+            console.clear();
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "create",
+        usage: "<#room>",
+        help: "Create a room and join it",
+        min_args: 1,
+        max_args: 1,
+        handler: |console, args| {
+            console.client().create_room(&room_name(&args[0]));
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "join",
+        usage: "<#room>",
+        help: "Join a room and start talking in it",
+        min_args: 1,
+        max_args: 1,
+        handler: |console, args| {
+            console.client().join_room(&room_name(&args[0]));
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "leave",
+        usage: "[#room]",
+        help: "Leave a room, the current one by default",
+        min_args: 0,
+        max_args: 1,
+        handler: |console, args| {
+            let room = args.first().map(|room| room_name(room))
+                .or_else(|| console.client().current_room())
+                .ok_or("You are not in any room")?;
+            console.client().leave_room(&room);
+            Ok(())
+        },
+    });
+    registry.register(Command {
+        name: "rooms",
+        usage: "",
+        help: "List the rooms on this server",
+        min_args: 0,
+        max_args: 0,
+        handler: |console, _| {
+            console.client().list_rooms();
+            Ok(())
+        },
+    });
+}
+
+fn help(console: &mut dyn Console, args: &[String]) -> Result<(), String> {
+    let help = console.commands().help(args.first().map(String::as_str))?;
+    console.print(&help);
+    Ok(())
+}
+
+fn me(console: &mut dyn Console, args: &[String]) -> Result<(), String> {
+    let message = console.client().action(&args[0]).map_err(|e| e.to_string())?;
+    console.echo(&message);
+    Ok(())
+}
+
+fn msg(console: &mut dyn Console, args: &[String]) -> Result<(), String> {
+    let message = console.client().direct_message(&args[0], &args[1]).map_err(|e| e.to_string())?;
+    console.echo(&message);
+    Ok(())
+}
+
+fn fingerprint(console: &mut dyn Console, args: &[String]) -> Result<(), String> {
+    let line = match args.first() {
+        Some(username) => format!("{}: {}", username, console.client().fingerprint(Some(username)).map_err(|e| e.to_string())?),
+        None => format!("Your key: {}", console.client().fingerprint(None).map_err(|e| e.to_string())?),
+    };
+    console.print(&line);
+    Ok(())
+}
+
+fn history(console: &mut dyn Console, args: &[String]) -> Result<(), String> {
+    let count = match args.first() {
+        Some(count) => count.parse::<usize>().map_err(|_| "Usage: /history [count]".to_string())?,
+        None => 10,
+    };
+    let room = console.client().current_room().ok_or("You are not in any room")?;
+    let transcript = console.client().transcript(&room, count).iter()
+        .map(|message| message.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    console.print(&transcript);
+    Ok(())
+}
+
+// Lets users type `/join builds` as well as `/join #builds`
+pub(crate) fn room_name(room: &str) -> String {
+    if room.starts_with('#') {
+        room.to_string()
+    } else {
+        format!("#{}", room)
+    }
+}
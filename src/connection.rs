@@ -0,0 +1,330 @@
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use log::{debug, error, trace};
+use rustls::ClientConnection;
+
+use crate::e2e::Identity;
+use crate::encoding::Encoding;
+use crate::find_server;
+use crate::frame::{FrameReader, write_frame};
+use crate::message::{Body, Message, now_nanos};
+use crate::message_types::MessageType;
+use crate::protocol::HANDSHAKE_TIMEOUT;
+use crate::tls;
+use crate::tls::Trust;
+
+pub(crate) type Reader = Box<dyn Read + Send>;
+pub(crate) type Writer = Box<dyn Write + Send>;
+
+// A connected socket, with a TLS session on top of it if the server was reached over TLS
+pub(crate) struct Transport {
+    socket: TcpStream,
+    session: Option<Arc<Mutex<ClientConnection>>>,
+    // Of the certificate the server presented
+    fingerprint: Option<String>,
+    // What the server agreed to in the handshake
+    encoding: Encoding,
+}
+
+impl Transport {
+    // Uses TLS if told which certificate to trust. Asks for `encoding`, but ends up with JSON if the
+    // server does not speak it.
+    pub(crate) fn connect(address: &str, tls: Option<Trust>, encoding: Encoding) -> io::Result<Transport> {
+        let mut socket = TcpStream::connect(address)?;
+        // Everything sent is a whole frame already, waiting to batch it only delays the chat
+        socket.set_nodelay(true)?;
+        let session = match tls {
+            Some(trust) => Some(tls::connect(&mut socket, address, trust)?),
+            None => None,
+        };
+        let fingerprint = session.as_ref()
+            .and_then(|session| session.peer_certificates()?.first().map(tls::fingerprint));
+        let mut transport = Transport {
+            socket,
+            session: session.map(|session| Arc::new(Mutex::new(session))),
+            fingerprint,
+            encoding: Encoding::Json,
+        };
+        transport.encoding = transport.hello(encoding)?;
+        Ok(transport)
+    }
+
+    pub(crate) fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // Agrees on a protocol version and encoding before anything else is said. The server stays quiet
+    // after its Welcome until spoken to, so nothing read here belongs to the session.
+    fn hello(&self, encoding: Encoding) -> io::Result<Encoding> {
+        let hello = Message::builder()
+            .message_type(MessageType::Hello)
+            .encoding(encoding)
+            .build();
+        write_frame(&mut self.writer()?, &Message::to_bytes(&[hello], Encoding::Json))?;
+        self.socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let frame = FrameReader::new(self.reader()?).read_frame();
+        self.socket.set_read_timeout(None)?;
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "The server hung up during the handshake")),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "The server did not answer the handshake, it is probably running an older version"));
+            }
+            Err(e) => return Err(e),
+        };
+        let reply = Message::from_bytes(&frame, Encoding::Json).ok()
+            .and_then(|messages| messages.into_iter().next())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                          "The server answered in a format this client does not understand, it is probably running an older version"))?;
+        match reply.body() {
+            Body::Welcome { version, agent, encoding } => {
+                debug!("Connected to {} speaking protocol version {} in {}", agent, version, encoding);
+                Encoding::from_name(encoding).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                                                                           format!("The server picked {}, which this client does not know", encoding)))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::ConnectionRefused, reply.get_message())),
+        }
+    }
+
+    pub(crate) fn fingerprint(&self) -> Option<String> {
+        self.fingerprint.clone()
+    }
+
+    pub(crate) fn reader(&self) -> io::Result<Reader> {
+        match &self.session {
+            Some(session) => Ok(Box::new(tls::split(session.clone(), &self.socket)?.0)),
+            None => Ok(Box::new(self.socket.try_clone()?)),
+        }
+    }
+
+    pub(crate) fn writer(&self) -> io::Result<Writer> {
+        match &self.session {
+            Some(session) => Ok(Box::new(tls::split(session.clone(), &self.socket)?.1)),
+            None => Ok(Box::new(self.socket.try_clone()?)),
+        }
+    }
+
+    // Lets the server know the session is over on purpose rather than cut off
+    fn close_notify(&self) {
+        if let Some(session) = &self.session {
+            let mut session = session.lock().unwrap();
+            session.send_close_notify();
+            if let Err(e) = session.write_tls(&mut &self.socket) {
+                debug!("Failed to send close_notify: {}", e);
+            }
+        }
+    }
+
+    fn shutdown(&self) {
+        if let Err(e) = self.socket.shutdown(Shutdown::Both) {
+            debug!("Failed to shut down socket: {}", e);
+        }
+    }
+}
+
+// The client's side of the link to the server. Writes go straight to the socket while it is up and
+// into the outbox while it is down, to be sent once the session has been resumed.
+pub(crate) struct Connection {
+    link: Mutex<Link>,
+    address: Mutex<String>,
+    discovery_port: u16,
+    // Certificate of the server the session started on. Reconnects have to use TLS too if the first
+    // connection did and find that same certificate, wherever discovery says the server is now,
+    // or the password would go to whoever answered.
+    fingerprint: Option<String>,
+    // What to ask the server for when reconnecting
+    encoding: Encoding,
+    // The name to reclaim after reconnecting, empty until the server has accepted one
+    username: Mutex<String>,
+    // Set when the username is a registered account, reconnecting has to log in again
+    password: Mutex<Option<String>>,
+    identity: Identity,
+    // When anything last arrived from the server, in nanoseconds
+    last_heard: AtomicI64,
+    closing: AtomicBool,
+}
+
+struct Link {
+    transport: Transport,
+    writer: Option<BufWriter<Writer>>,
+    outbox: Vec<Message>,
+}
+
+impl Connection {
+    pub(crate) fn new(transport: Transport,
+                      address: &str,
+                      discovery_port: u16,
+                      encoding: Encoding,
+                      identity: Identity) -> Connection {
+        let writer = BufWriter::new(transport.writer().expect("Failed to create client BufWriter"));
+        Connection {
+            fingerprint: transport.fingerprint(),
+            encoding,
+            link: Mutex::new(Link {
+                transport,
+                writer: Some(writer),
+                outbox: Vec::new(),
+            }),
+            address: Mutex::new(address.to_string()),
+            discovery_port,
+            username: Mutex::new(String::new()),
+            password: Mutex::new(None),
+            identity,
+            last_heard: AtomicI64::new(now_nanos()),
+            closing: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn send(&self, message: &Message) {
+        let mut link = self.link.lock().unwrap();
+        let encoding = link.transport.encoding();
+        if let Some(writer) = link.writer.as_mut() {
+            match write_frame(writer, &Message::to_bytes(std::slice::from_ref(message), encoding)) {
+                Ok(()) => return,
+                Err(e) => {
+                    error!("Failed to flush buffer: {}", e);
+                    link.writer = None;
+                }
+            }
+        }
+        trace!("Offline, holding on to {}", message);
+        link.outbox.push(message.clone());
+    }
+
+    // A second handle on the current socket for the receive thread to read from
+    pub(crate) fn reader(&self) -> Reader {
+        self.link.lock().unwrap().transport.reader().expect("Failed to create client FrameReader")
+    }
+
+    pub(crate) fn go_offline(&self) {
+        self.link.lock().unwrap().writer = None;
+    }
+
+    // How frames on the current socket are encoded
+    pub(crate) fn encoding(&self) -> Encoding {
+        self.link.lock().unwrap().transport.encoding()
+    }
+
+    pub(crate) fn is_online(&self) -> bool {
+        self.link.lock().unwrap().writer.is_some()
+    }
+
+    // Makes a new, already resumed socket the live one and sends whatever piled up in the meantime.
+    // Returns how many buffered messages went out.
+    pub(crate) fn go_online(&self, transport: Transport) -> io::Result<usize> {
+        let mut writer = BufWriter::new(transport.writer()?);
+        let mut link = self.link.lock().unwrap();
+        let outbox = std::mem::take(&mut link.outbox);
+        if !outbox.is_empty() {
+            write_frame(&mut writer, &Message::to_bytes(&outbox, transport.encoding()))?;
+        }
+        link.transport = transport;
+        link.writer = Some(writer);
+        self.heard();
+        Ok(outbox.len())
+    }
+
+    // One attempt at reaching the server, first where it was and then wherever discovery finds it
+    pub(crate) fn dial(&self) -> Option<Transport> {
+        let address = self.address.lock().unwrap().clone();
+        let trust = || self.fingerprint.as_deref().map(Trust::Pinned);
+        match Transport::connect(&address, trust(), self.encoding) {
+            Ok(transport) => return Some(transport),
+            Err(e) => report_dial_error(&address, &e),
+        }
+        let found = find_server::get_ip(self.discovery_port)?.address.to_string();
+        if found == address {
+            return None;
+        }
+        match Transport::connect(&found, trust(), self.encoding) {
+            Ok(transport) => {
+                debug!("Server moved from {} to {}", address, found);
+                *self.address.lock().unwrap() = found;
+                Some(transport)
+            }
+            Err(e) => {
+                report_dial_error(&found, &e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn username(&self) -> String {
+        self.username.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_username(&self, username: &str) {
+        *self.username.lock().unwrap() = username.to_string();
+    }
+
+    pub(crate) fn password(&self) -> Option<String> {
+        self.password.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_password(&self, password: Option<String>) {
+        *self.password.lock().unwrap() = password;
+    }
+
+    // Where the server was last reached, which changes if it moves while reconnecting
+    pub(crate) fn address(&self) -> String {
+        self.address.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_tls(&self) -> bool {
+        self.fingerprint.is_some()
+    }
+
+    pub(crate) fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    // Tells the server which key direct messages to us should be encrypted for
+    pub(crate) fn public_key_message(&self) -> Message {
+        Message::builder()
+            .username(&self.username())
+            .message(&self.identity.public_key())
+            .message_type(MessageType::PublicKey)
+            .build()
+    }
+
+    pub(crate) fn heard(&self) {
+        self.last_heard.store(now_nanos(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn silent_for(&self) -> Duration {
+        Duration::from_nanos((now_nanos() - self.last_heard.load(Ordering::Relaxed)).max(0) as u64)
+    }
+
+    pub(crate) fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+        let link = self.link.lock().unwrap();
+        link.transport.close_notify();
+        link.transport.shutdown();
+    }
+
+    // Cuts the current socket, the receive thread then notices and starts reconnecting
+    pub(crate) fn hang_up(&self) {
+        self.link.lock().unwrap().transport.shutdown();
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+}
+
+// An unreachable server is expected while reconnecting, a TLS failure such as a changed certificate
+// is not and the user needs to see it
+fn report_dial_error(address: &str, e: &io::Error) {
+    if e.kind() == io::ErrorKind::InvalidData {
+        error!("Could not connect to {}: {}", address, e);
+    } else {
+        debug!("Could not reach {}: {}", address, e);
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::tls::config_dir;
+use crate::usernames;
+
+const IDENTITY_FILE: &str = "identity.key";
+const KNOWN_KEYS_FILE: &str = "known_keys";
+const KEY_LABEL: &[u8] = b"quick_chat direct message v1";
+
+// Rides along with an encrypted direct message. Carrying both public keys lets either end derive
+// the key again later, e.g. when the message comes back out of history after a key change.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Envelope {
+    pub(crate) sender_key: String,
+    pub(crate) recipient_key: String,
+    pub(crate) nonce: String,
+}
+
+// This user's long-term X25519 key pair, kept next to the TLS files and never sent anywhere but
+// the public half
+pub(crate) struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+    peers: KnownKeys,
+}
+
+impl Identity {
+    pub(crate) fn load_or_create() -> io::Result<Identity> {
+        let path = config_dir().join(IDENTITY_FILE);
+        let secret = match fs::read(&path) {
+            Ok(bytes) => {
+                let bytes: [u8; 32] = bytes.try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData,
+                                                format!("{} is not a valid key", path.display())))?;
+                debug!("Loaded identity from {}", path.display());
+                StaticSecret::from(bytes)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let secret = StaticSecret::random_from_rng(OsRng);
+                save_secret(&path, &secret)?;
+                info!("Generated a new identity in {}", path.display());
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+        let public = PublicKey::from(&secret);
+        Ok(Identity {
+            secret,
+            public,
+            peers: KnownKeys {
+                path: config_dir().join(KNOWN_KEYS_FILE),
+            },
+        })
+    }
+
+    pub(crate) fn public_key(&self) -> String {
+        STANDARD.encode(self.public.as_bytes())
+    }
+
+    // Encrypts `text` so only this user and the owner of `recipient_key` can read it. The usernames
+    // are authenticated too, so the server can't pass the message off as coming from someone else.
+    pub(crate) fn seal(&self, text: &str, sender: &str, recipient: &str, recipient_key: &str)
+                       -> Result<(String, Envelope), String> {
+        self.peers.check(recipient, recipient_key)?;
+        let cipher = self.cipher(recipient_key)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload {
+            msg: text.as_bytes(),
+            aad: associated_data(sender, recipient).as_bytes(),
+        }).map_err(|_| "Encryption failed".to_string())?;
+        Ok((STANDARD.encode(ciphertext), Envelope {
+            sender_key: self.public_key(),
+            recipient_key: recipient_key.to_string(),
+            nonce: STANDARD.encode(nonce),
+        }))
+    }
+
+    // `username` is who we are. Our key only vouches for our own side of the conversation, the key
+    // in the envelope for the other side has to be the one pinned for them, or the server could
+    // put its own there and speak as anyone.
+    pub(crate) fn open(&self, ciphertext: &str, sender: &str, recipient: &str, username: &str, envelope: &Envelope)
+                       -> Result<String, String> {
+        let own_key = self.public_key();
+        let (peer, peer_key) = if envelope.sender_key == own_key && usernames::same_user(sender, username) {
+            (recipient, &envelope.recipient_key)
+        } else if envelope.recipient_key == own_key && usernames::same_user(recipient, username) {
+            (sender, &envelope.sender_key)
+        } else {
+            return Err("Message was encrypted for a different key".to_string());
+        };
+        // Nobody but us can have encrypted for our key on both ends
+        if *peer_key != own_key {
+            self.peers.check(peer, peer_key)?;
+        }
+        let cipher = self.cipher(peer_key)?;
+        let nonce = STANDARD.decode(&envelope.nonce).map_err(|e| e.to_string())?;
+        if nonce.len() != 12 {
+            return Err("Malformed nonce".to_string());
+        }
+        let ciphertext = STANDARD.decode(ciphertext).map_err(|e| e.to_string())?;
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), Payload {
+            msg: &ciphertext,
+            aad: associated_data(sender, recipient).as_bytes(),
+        }).map_err(|_| "Message failed authentication".to_string())?;
+        String::from_utf8(plaintext).map_err(|e| e.to_string())
+    }
+
+    fn cipher(&self, peer_key: &str) -> Result<ChaCha20Poly1305, String> {
+        let peer = decode_key(peer_key)?;
+        let shared = self.secret.diffie_hellman(&peer);
+        if !shared.was_contributory() {
+            return Err("Refusing a low order public key".to_string());
+        }
+        let key = Sha256::new()
+            .chain_update(KEY_LABEL)
+            .chain_update(shared.as_bytes())
+            .finalize();
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+// Trust on first use for the people we talk to: the first key seen for a username is written to
+// known_keys, and messages to or from them under any other key are refused from then on
+struct KnownKeys {
+    path: PathBuf,
+}
+
+impl KnownKeys {
+    fn pinned(&self, username: &str) -> Option<String> {
+        let canonical = usernames::canonical(username);
+        fs::read_to_string(&self.path).ok()?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(pinned_user, _)| *pinned_user == canonical)
+            .map(|(_, key)| key.trim().to_string())
+    }
+
+    fn pin(&self, username: &str, key: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} {}", usernames::canonical(username), key)
+    }
+
+    fn check(&self, username: &str, key: &str) -> Result<(), String> {
+        match self.pinned(username) {
+            Some(pinned) if pinned == key => Ok(()),
+            Some(pinned) => Err(format!(
+                "{}'s key changed from {} to {}. If they changed it, remove their line from {}",
+                username, fingerprint(&pinned), fingerprint(key), self.path.display())),
+            None => {
+                info!("Trusting {}'s key on first use, fingerprint {}", username, fingerprint(key));
+                self.pin(username, key).map_err(|e| format!("Could not pin {}'s key: {}", username, e))
+            }
+        }
+    }
+}
+
+fn save_secret(path: &Path, secret: &StaticSecret) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&secret.to_bytes())
+}
+
+fn decode_key(key: &str) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = STANDARD.decode(key)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "Public key has the wrong length".to_string())?;
+    Ok(PublicKey::from(bytes))
+}
+
+// Canonical, since the server stores the recipient under the name they are online as rather than
+// however the sender spelt it
+fn associated_data(sender: &str, recipient: &str) -> String {
+    format!("{}\n{}", usernames::canonical(sender), usernames::canonical(recipient))
+}
+
+// Short enough to read out over the phone, long enough that a substituted key won't match
+pub(crate) fn fingerprint(public_key: &str) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    digest[..16].chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
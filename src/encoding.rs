@@ -0,0 +1,36 @@
+use clap::ValueEnum;
+
+/// How messages are written on the wire once connected. Client and server settle on one during
+/// the handshake, which is itself always JSON so that any two versions can understand each other.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Readable, and spoken by every version
+    Json,
+    /// MessagePack, more compact and quicker to read and write than JSON
+    MessagePack,
+}
+
+impl Encoding {
+    // What the encoding is called in the handshake
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    // None for encodings only a newer peer knows
+    pub(crate) fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -1,46 +1,80 @@
 use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
 use crate::adapter;
+use crate::DEFAULT_SERVER_PORT;
 
-pub(crate) fn get_ip() -> Option<IpAddr> {
+/// A server that answered a discovery broadcast.
+pub struct Discovered {
+    pub address: SocketAddr,
+    /// Whether it accepts TLS connections.
+    pub tls: bool,
+}
+
+/// Broadcasts on the local network for a server listening for discovery on `discovery_port`,
+/// waiting up to five seconds for an answer.
+pub fn get_ip(discovery_port: u16) -> Option<Discovered> {
     const REQUEST_MESSAGE: &[u8] = "DISCOVER_CHAT_SERVER_REQUEST".as_bytes();
 
     // Open a random port to send the package
-    let socket = UdpSocket::bind("0.0.0.0:1234").expect("Failed to bind socket");
-    debug!("Opening Socket: {:?}", socket.local_addr().unwrap());
-    socket.set_broadcast(true).expect("Failed to set broadcast");
+    let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|socket| socket.set_broadcast(true).map(|()| socket)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Failed to open a socket for discovery: {}", e);
+            return None;
+        }
+    };
+    debug!("Opening Socket: {:?}", socket.local_addr());
     debug!("Enabled broadcast");
-    socket.set_read_timeout(Some(Duration::new(5, 0))).expect("Failed to set timeout");
 
     // Try the 255.255.255.255 first
-    let broadcast_addr = SocketAddr::new(IpAddr::from_str("255.255.255.255").unwrap(), 8888);
+    let broadcast_addr = SocketAddr::new(IpAddr::from_str("255.255.255.255").unwrap(), discovery_port);
     debug!("Broadcasting to: {:?}\n", broadcast_addr);
-    socket.send_to(REQUEST_MESSAGE, broadcast_addr).expect("Failed to send broadcast");
+    if let Err(e) = socket.send_to(REQUEST_MESSAGE, broadcast_addr) {
+        debug!("Failed to broadcast to {}: {}", broadcast_addr, e);
+    }
 
-    for mut adapt in adapter::get_adapters() {
-        let broadcast_addr = adapt.broadcast_address();
-        if broadcast_addr.is_some() {
+    for adapt in adapter::get_adapters() {
+        if let Some(broadcast_addr) = adapt.broadcast_address() {
             trace!("Adaptor Name: {:?}", adapt.get_adapter_name());
             debug!("Broadcasting to: {:?}\n", broadcast_addr);
-            socket
-                .send_to(REQUEST_MESSAGE, SocketAddr::new(broadcast_addr.unwrap(), 1234))
-                .expect("Failed to send broadcast");
+            if let Err(e) = socket.send_to(REQUEST_MESSAGE, SocketAddr::new(broadcast_addr, discovery_port)) {
+                debug!("Failed to broadcast to {}: {}", broadcast_addr, e);
+            }
         }
     }
 
     debug!("Waiting for a reply from Server!");
-    // Wait for a response
+    // Wait for a response, skipping anything else that lands on the port meanwhile
+    let deadline = Instant::now() + Duration::new(5, 0);
     let mut receive_buf = [0; 15000];
-    let (received_bytes, server_addr) = socket.recv_from(&mut receive_buf).expect("Failed to receive packet");
-    let message = String::from_utf8(receive_buf[..received_bytes].to_vec()).expect("Failed to convert packet data to string");
-    // Check if the message is correct
-    if message.trim() == "DISCOVER_CHAT_SERVER_RESPONSE" {
-        debug!("Broadcast response from server: {}", server_addr.ip());
-        return Some(server_addr.ip());
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+        let (received_bytes, server_addr) = match socket.recv_from(&mut receive_buf) {
+            Ok(received) => received,
+            Err(_) => break,
+        };
+        let message = String::from_utf8_lossy(&receive_buf[..received_bytes]);
+        // Check if the message is correct, older servers do not send their port
+        let mut response = message.trim().splitn(3, ':');
+        if response.next() == Some("DISCOVER_CHAT_SERVER_RESPONSE") {
+            let port = response.next()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(DEFAULT_SERVER_PORT);
+            let tls = response.next() == Some("tls");
+            debug!("Broadcast response from server: {}:{} (TLS: {})", server_addr.ip(), port, tls);
+            return Some(Discovered {
+                address: SocketAddr::new(server_addr.ip(), port),
+                tls,
+            });
+        }
+        debug!("Ignoring a stray packet from {}", server_addr);
     }
 
     info!("Timeout: No response from Server!");
@@ -0,0 +1,193 @@
+use std::io;
+use std::io::{Read, Write};
+
+use log::trace;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Every frame on the wire is a 4 byte big-endian length followed by that many bytes of payload.
+const HEADER_LEN: usize = 4;
+pub(crate) const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+fn encode_frame(payload: &[u8]) -> io::Result<Vec<u8>> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Frame of {} bytes exceeds the {} byte limit", payload.len(), MAX_FRAME_LEN),
+        ));
+    }
+    trace!("Writing frame of {} bytes", payload.len());
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+pub(crate) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(payload)?)?;
+    writer.flush()
+}
+
+pub(crate) async fn write_frame_async<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(payload)?).await?;
+    writer.flush().await
+}
+
+// Collects raw bytes as they arrive and hands back complete frames, no matter how the
+// underlying reads split or merge them.
+pub(crate) struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub(crate) fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::new(),
+        }
+    }
+
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&self.buffer[..HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Incoming frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_LEN),
+            ));
+        }
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let frame = self.buffer[HEADER_LEN..HEADER_LEN + len].to_vec();
+        self.buffer.drain(..HEADER_LEN + len);
+        trace!("Decoded frame of {} bytes", len);
+        Ok(Some(frame))
+    }
+}
+
+pub(crate) struct FrameReader<R: Read> {
+    reader: R,
+    decoder: FrameDecoder,
+    buf: [u8; 8192],
+}
+
+impl<R: Read> FrameReader<R> {
+    pub(crate) fn new(reader: R) -> FrameReader<R> {
+        FrameReader {
+            reader,
+            decoder: FrameDecoder::new(),
+            buf: [0u8; 8192],
+        }
+    }
+
+    // Blocks until a whole frame is available. Returns None once the peer closes the connection.
+    pub(crate) fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+            let amt = self.reader.read(&mut self.buf)?;
+            if amt == 0 {
+                return Ok(None);
+            }
+            self.decoder.extend(&self.buf[..amt]);
+        }
+    }
+}
+
+pub(crate) struct AsyncFrameReader<R: AsyncRead + Unpin> {
+    reader: R,
+    decoder: FrameDecoder,
+    buf: [u8; 8192],
+}
+
+impl<R: AsyncRead + Unpin> AsyncFrameReader<R> {
+    pub(crate) fn new(reader: R) -> AsyncFrameReader<R> {
+        AsyncFrameReader {
+            reader,
+            decoder: FrameDecoder::new(),
+            buf: [0u8; 8192],
+        }
+    }
+
+    // The next byte without consuming it, None once the peer closes the connection
+    pub(crate) async fn peek(&mut self) -> io::Result<Option<u8>> {
+        while self.decoder.buffer.is_empty() {
+            let amt = self.reader.read(&mut self.buf).await?;
+            if amt == 0 {
+                return Ok(None);
+            }
+            self.decoder.extend(&self.buf[..amt]);
+        }
+        Ok(self.decoder.buffer.first().copied())
+    }
+
+    // Same as FrameReader::read_frame without tying up a thread while waiting
+    pub(crate) async fn read_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+            let amt = self.reader.read(&mut self.buf).await?;
+            if amt == 0 {
+                return Ok(None);
+            }
+            self.decoder.extend(&self.buf[..amt]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_merged_frames() {
+        let mut bytes = encode_frame(b"first").unwrap();
+        bytes.extend(encode_frame(b"second").unwrap());
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"second".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn joins_split_frame() {
+        let bytes = encode_frame(b"split across reads").unwrap();
+        let mut decoder = FrameDecoder::new();
+        // Through the header and then the payload a few bytes at a time
+        for chunk in bytes.chunks(3) {
+            assert_eq!(decoder.next_frame().unwrap(), None);
+            decoder.extend(chunk);
+        }
+        assert_eq!(decoder.next_frame().unwrap(), Some(b"split across reads".to_vec()));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn decodes_large_frame() {
+        let payload: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let bytes = encode_frame(&payload).unwrap();
+        let mut decoder = FrameDecoder::new();
+        for chunk in bytes.chunks(8192) {
+            decoder.extend(chunk);
+        }
+        assert_eq!(decoder.next_frame().unwrap(), Some(payload));
+    }
+
+    #[test]
+    fn rejects_oversized_frame() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        let error = decoder.next_frame().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
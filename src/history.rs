@@ -0,0 +1,294 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::legacy::LegacyMessage;
+use crate::message::Message;
+use crate::message_types::MessageType;
+
+const DEFAULT_FETCH_LIMIT: usize = 100;
+const MAX_FETCH_LIMIT: usize = 1000;
+
+/// Which stored messages to fetch.
+///
+/// Sequence numbers are exclusive bounds. With `after` set the oldest matching messages are
+/// returned so a client can page forwards through what it missed, otherwise the newest ones are.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct HistoryQuery {
+    /// Only messages older than this sequence number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<u64>,
+    /// Only messages newer than this sequence number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+    /// At most this many messages, 100 by default and never more than 1000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Only messages in this room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+impl HistoryQuery {
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_FETCH_LIMIT).min(MAX_FETCH_LIMIT)
+    }
+
+    pub(crate) fn select(&self, messages: &[Message], visible: &dyn Fn(&Message) -> bool) -> Vec<Message> {
+        let matching = messages.iter().filter(|message| {
+            self.before.is_none_or(|before| message.get_seq() < before)
+                && self.after.is_none_or(|after| message.get_seq() > after)
+                && self.room.as_ref().is_none_or(|room| &message.get_room() == room)
+                && visible(message)
+        });
+        if self.after.is_some() {
+            matching.take(self.limit()).cloned().collect()
+        } else {
+            let mut newest: Vec<Message> = matching.rev().take(self.limit()).cloned().collect();
+            newest.reverse();
+            newest
+        }
+    }
+}
+
+/// Where a server keeps the messages it has relayed.
+pub trait History: Send {
+    fn append(&mut self, message: &Message) -> io::Result<()>;
+
+    // `visible` lets the server hide messages the requesting client is not allowed to see
+    fn query(&self, query: &HistoryQuery, visible: &dyn Fn(&Message) -> bool) -> Vec<Message>;
+
+    // Sequence number of the newest stored message, 0 when empty
+    fn last_seq(&self) -> u64;
+
+    // Every room something was said in or joined, so rooms outlive a restart along with their
+    // history. Histories that cannot tell leave it empty and only the default room exists.
+    fn rooms(&self) -> BTreeSet<String> {
+        BTreeSet::new()
+    }
+
+    // Makes sure everything appended so far survives the process exiting
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Keeps history for the lifetime of the process only.
+#[derive(Default)]
+pub struct MemoryHistory {
+    messages: Vec<Message>,
+}
+
+impl MemoryHistory {
+    pub fn new() -> MemoryHistory {
+        MemoryHistory {
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl History for MemoryHistory {
+    fn append(&mut self, message: &Message) -> io::Result<()> {
+        self.messages.push(message.clone());
+        Ok(())
+    }
+
+    fn query(&self, query: &HistoryQuery, visible: &dyn Fn(&Message) -> bool) -> Vec<Message> {
+        query.select(&self.messages, visible)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.messages.last().map_or(0, Message::get_seq)
+    }
+
+    fn rooms(&self) -> BTreeSet<String> {
+        rooms_of(&self.messages)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Append-only log with one JSON encoded message per line, replayed into memory on open.
+pub struct FileHistory {
+    messages: Vec<Message>,
+    writer: BufWriter<File>,
+}
+
+impl FileHistory {
+    pub fn open(path: &Path) -> io::Result<FileHistory> {
+        let mut messages = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_entry(&line) {
+                    Ok(mut message) => {
+                        // Logs written before messages were sequenced get numbered in file order
+                        if message.get_seq() == 0 {
+                            let seq = messages.last().map_or(0, Message::get_seq) + 1;
+                            message.assign_seq(seq);
+                        }
+                        messages.push(message)
+                    }
+                    // A crash mid-write can leave a partial last line behind
+                    Err(e) => warn!("Skipping unreadable history entry on line {} of {}: {}",
+                                    index + 1, path.display(), e),
+                }
+            }
+        }
+        debug!("Loaded {} messages from {}", messages.len(), path.display());
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileHistory {
+            messages,
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl History for FileHistory {
+    fn append(&mut self, message: &Message) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, message)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.messages.push(message.clone());
+        Ok(())
+    }
+
+    fn query(&self, query: &HistoryQuery, visible: &dyn Fn(&Message) -> bool) -> Vec<Message> {
+        query.select(&self.messages, visible)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.messages.last().map_or(0, Message::get_seq)
+    }
+
+    fn rooms(&self) -> BTreeSet<String> {
+        rooms_of(&self.messages)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
+fn rooms_of(messages: &[Message]) -> BTreeSet<String> {
+    messages.iter()
+        .filter(|message| matches!(message.get_type(),
+            MessageType::Message | MessageType::Action | MessageType::Join | MessageType::Leave))
+        .map(Message::get_room)
+        .collect()
+}
+
+// Entries written before messages were tagged by type are converted as they are read
+fn parse_entry(line: &str) -> Result<Message, String> {
+    match serde_json::from_str::<Message>(line) {
+        Ok(message) => Ok(message),
+        Err(e) => match serde_json::from_str::<LegacyMessage>(line) {
+            Ok(legacy) => legacy.into_message().ok_or_else(|| "unknown message type".to_string()),
+            Err(_) => Err(e.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::message::DEFAULT_ROOM;
+
+    use super::*;
+
+    // Sequence numbers 1 to 10, odd ones in #general and even ones in #ops
+    fn messages() -> Vec<Message> {
+        (1..=10).map(|seq| {
+            let room = if seq % 2 == 0 { "#ops" } else { "#general" };
+            let mut message = Message::builder()
+                .message(&seq.to_string())
+                .message_type(MessageType::Message)
+                .room(room)
+                .build();
+            message.assign_seq(seq);
+            message
+        }).collect()
+    }
+
+    fn seqs(query: HistoryQuery) -> Vec<u64> {
+        query.select(&messages(), &|_| true).iter().map(Message::get_seq).collect()
+    }
+
+    #[test]
+    fn select_bounds_are_exclusive() {
+        assert_eq!(seqs(HistoryQuery::default()), (1..=10).collect::<Vec<_>>());
+        assert_eq!(seqs(HistoryQuery { before: Some(5), ..Default::default() }), [1, 2, 3, 4]);
+        assert_eq!(seqs(HistoryQuery { after: Some(7), ..Default::default() }), [8, 9, 10]);
+        assert_eq!(seqs(HistoryQuery { after: Some(2), before: Some(6), ..Default::default() }), [3, 4, 5]);
+    }
+
+    #[test]
+    fn select_limit_keeps_the_end_it_pages_from() {
+        // Paging forwards from `after` gets the oldest, anything else the newest
+        assert_eq!(seqs(HistoryQuery { after: Some(2), limit: Some(3), ..Default::default() }), [3, 4, 5]);
+        assert_eq!(seqs(HistoryQuery { before: Some(9), limit: Some(3), ..Default::default() }), [6, 7, 8]);
+        assert_eq!(seqs(HistoryQuery { limit: Some(2), ..Default::default() }), [9, 10]);
+        assert_eq!(HistoryQuery { limit: Some(5000), ..Default::default() }.limit(), MAX_FETCH_LIMIT);
+    }
+
+    #[test]
+    fn select_by_room_and_visibility() {
+        let ops = || HistoryQuery { room: Some("#ops".to_string()), ..Default::default() };
+        assert_eq!(seqs(ops()), [2, 4, 6, 8, 10]);
+        assert_eq!(seqs(HistoryQuery { limit: Some(2), ..ops() }), [8, 10]);
+        assert_eq!(seqs(HistoryQuery { after: Some(3), limit: Some(2), ..ops() }), [4, 6]);
+        let visible = ops().select(&messages(), &|message| message.get_seq() > 5);
+        assert_eq!(visible.iter().map(Message::get_seq).collect::<Vec<_>>(), [6, 8, 10]);
+    }
+
+    #[test]
+    fn file_history_reads_legacy_entries() {
+        let path = env::temp_dir().join(format!("quick_chat_history_{}.log", Uuid::new_v4()));
+        let current = serde_json::to_string(&Message::builder()
+            .username("carol")
+            .message("current")
+            .message_type(MessageType::Message)
+            .build()).unwrap();
+        let lines = [
+            r##"{"username":"alice","message":"no room","timestamp":1,"type_":32}"##,
+            "",
+            r##"{"username":"bob","message":"","timestamp":2,"type_":1,"room":"#ops"}"##,
+            r##"{"username":"bob","message":"never meant anything","timestamp":3,"type_":99}"##,
+            &current,
+            r##"{"username":"bob","message":"cut o"##,
+        ];
+        fs::write(&path, lines.join("\n")).unwrap();
+
+        let history = FileHistory::open(&path).unwrap();
+        let loaded = history.query(&HistoryQuery::default(), &|_| true);
+        fs::remove_file(&path).unwrap();
+
+        // Unknown types and the partial last line are skipped, the rest numbered in file order
+        let summary: Vec<(u64, MessageType, String, String)> = loaded.iter()
+            .map(|message| (message.get_seq(), message.get_type(), message.get_username(), message.get_room()))
+            .collect();
+        assert_eq!(summary, [
+            (1, MessageType::Message, "alice".to_string(), DEFAULT_ROOM.to_string()),
+            (2, MessageType::Join, "bob".to_string(), "#ops".to_string()),
+            (3, MessageType::Message, "carol".to_string(), DEFAULT_ROOM.to_string()),
+        ]);
+        assert_eq!(loaded[0].get_message(), "no room");
+        assert_eq!(history.last_seq(), 3);
+        assert!(history.rooms().contains("#ops"));
+    }
+}
@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+
+use crate::message::Message;
+
+/// The client a hook is being told about.
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    pub address: SocketAddr,
+    /// Empty until the client has picked a username or logged in.
+    pub username: String,
+    /// Whether the connection is encrypted.
+    pub tls: bool,
+}
+
+/// Lets an embedding program watch and shape what goes through a
+/// [`ChatServer`](crate::ChatServer). Every method has a default that changes nothing, so only the
+/// interesting ones need implementing.
+///
+/// Hooks run on the server's worker threads in the order they were added, and must not block.
+pub trait ServerHook: Send + Sync {
+    /// A client has connected. Returning false turns it away before it can send anything.
+    fn on_connect(&self, _client: &ClientInfo) -> bool {
+        true
+    }
+
+    /// A client sent a chat message, an action or a direct message. It can be changed in place
+    /// before it is stored and relayed, or dropped by returning false, in which case the sender is
+    /// told it was not delivered. Direct messages are end-to-end encrypted, so only their routing
+    /// can be looked at.
+    fn on_message(&self, _client: &ClientInfo, _message: &mut Message) -> bool {
+        true
+    }
+
+    /// A client has disconnected, or was dropped by the server.
+    fn on_leave(&self, _client: &ClientInfo) {}
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::e2e::Envelope;
+use crate::history::HistoryQuery;
+use crate::message::{DEFAULT_ROOM, Message, now_nanos};
+use crate::message_types::MessageType;
+use crate::presence::Presence;
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+// A message as protocol version 1 had it: the same flat shape for every kind, told apart by a
+// number. History files written back then are made of these, and so is everything a client from
+// before the handshake sends.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LegacyMessage {
+    #[serde(default)]
+    id: Uuid,
+    #[serde(default)]
+    seq: u64,
+    username: String,
+    message: String,
+    timestamp: i64,
+    type_: i32,
+    #[serde(default = "default_room")]
+    room: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    users: Vec<Presence>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<HistoryQuery>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    envelope: Option<Envelope>,
+}
+
+impl LegacyMessage {
+    // None if the number never meant anything
+    pub(crate) fn into_message(self) -> Option<Message> {
+        let type_ = MessageType::from_legacy_code(self.type_)?;
+        let mut builder = Message::builder();
+        builder.username(&self.username)
+            .message(&self.message)
+            .timestamp(self.timestamp)
+            .room(&self.room)
+            .users(self.users);
+        // The room list used to be a single line of text
+        if type_ == MessageType::ListRooms {
+            builder.rooms(self.message.split(", ").map(String::from).collect());
+        }
+        if let Some(recipient) = &self.recipient {
+            builder.recipient(recipient);
+        }
+        if let Some(query) = self.query {
+            builder.query(query);
+        }
+        if let Some(envelope) = self.envelope {
+            builder.envelope(envelope);
+        }
+        let mut message = builder.message_type(type_).build();
+        message.restore_id(self.id, self.seq);
+        Some(message)
+    }
+}
+
+// Whether a frame is made of version 1 messages, i.e. came from a client that predates the handshake
+pub(crate) fn is_legacy_frame(bytes: &[u8]) -> bool {
+    serde_json::from_slice::<Vec<LegacyMessage>>(bytes).is_ok()
+}
+
+// An error in the version 1 format, which is all a client from before the handshake can read
+pub(crate) fn upgrade_notice() -> Vec<u8> {
+    let notice = LegacyMessage {
+        id: Uuid::nil(),
+        seq: 0,
+        username: String::new(),
+        message: "This server speaks a newer protocol, upgrade quick_chat to talk to it".to_string(),
+        timestamp: now_nanos(),
+        type_: MessageType::Error.legacy_code().unwrap_or_default(),
+        room: default_room(),
+        recipient: None,
+        users: Vec::new(),
+        query: None,
+        envelope: None,
+    };
+    serde_json::to_vec(&[notice]).expect("Failed to serialize messages")
+}
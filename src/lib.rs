@@ -0,0 +1,46 @@
+//! QuickChat, a small chat server and client for the local network.
+//!
+//! [`ChatClient`] is the way to talk to a server from your own code, the `quick_chat` binary's
+//! terminal interface is built on nothing else. [`ChatServer`] hosts a server inside your own
+//! program, and [`ServerHook`] lets it watch, rewrite or drop the traffic.
+
+mod find_server;
+mod server_discovery_thread;
+mod server;
+mod client_handler;
+mod client;
+mod message;
+mod message_types;
+mod adapter;
+mod frame;
+mod history;
+mod presence;
+mod outbound;
+mod connection;
+mod tls;
+mod e2e;
+mod accounts;
+mod hooks;
+mod usernames;
+mod protocol;
+mod legacy;
+mod encoding;
+
+pub use accounts::Accounts;
+pub use client::{ChatClient, ChatError, ConnectOptions, Event};
+pub use encoding::Encoding;
+pub use find_server::{Discovered, get_ip as discover};
+pub use history::{FileHistory, History, HistoryQuery, MemoryHistory};
+pub use hooks::{ClientInfo, ServerHook};
+pub use message::{DEFAULT_ROOM, Message};
+pub use message_types::MessageType;
+pub use outbound::{DEFAULT_QUEUE_SIZE, OverflowPolicy, QueueConfig};
+pub use presence::Presence;
+pub use protocol::PROTOCOL_VERSION;
+pub use server::{ChatServer, ChatServerBuilder, TlsMode};
+pub use tls::acceptor as tls_acceptor;
+
+/// Port the server takes chat connections on unless told otherwise.
+pub const DEFAULT_SERVER_PORT: u16 = 42069;
+/// UDP port servers answer discovery broadcasts on unless told otherwise.
+pub const DEFAULT_DISCOVERY_PORT: u16 = 8888;
@@ -1,45 +1,201 @@
-use std::net::TcpStream;
+use std::io;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
+use clap::Parser;
 use env_logger::{Builder, Target};
 use log::{error, info};
 
-mod find_server;
-mod server_discovery_thread;
-mod server;
-mod client_handler;
-mod client;
-mod message;
-mod message_types;
-mod adapter;
+use quick_chat::{Accounts, ChatClient, ChatServer, ConnectOptions, FileHistory, History, MemoryHistory, QueueConfig, TlsMode};
+
+use crate::cli::{Cli, Command};
+use crate::terminal::Terminal;
+use crate::tui::{LogWriter, Tui};
+
+mod cli;
+mod commands;
+mod terminal;
+mod tui;
 
 fn main() {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or_default();
+    // The full-screen interface needs a terminal on both ends, anything else gets plain lines
+    let full_screen = !matches!(command, Command::Serve { .. })
+        && io::stdin().is_terminal() && io::stdout().is_terminal();
+
     let mut builder = Builder::from_default_env();
-    builder.target(Target::Stdout);
+    if full_screen {
+        builder.target(Target::Pipe(Box::new(LogWriter::default())));
+    } else {
+        builder.target(Target::Stdout);
+    }
     builder.init();
 
-    let mut ip = find_server::get_ip();
-    if ip.is_none() {
-        info!("Starting Server");
-        let s = server::Server::new().unwrap();
-        thread::spawn(move || {
-            s.run().unwrap();
-        });
-
-        // sleep for 2 seconds
-        thread::sleep(std::time::Duration::from_secs(2));
-        ip = find_server::get_ip();
-        // check if ip is none
-        if ip.is_none() {
-            error!("Could not find server");
+    match command {
+        Command::Serve { bind, discovery_port, history, accounts, require_login, queue_size, overflow, tls, require_tls, cert, key } => {
+            let history: Box<dyn History> = match history {
+                Some(path) => Box::new(FileHistory::open(&path).unwrap_or_else(|e| {
+                    error!("Could not open history file {}: {}", path.display(), e);
+                    exit(1);
+                })),
+                None => Box::new(MemoryHistory::new()),
+            };
+            let mut accounts = match accounts {
+                Some(path) => Accounts::open(&path).unwrap_or_else(|e| {
+                    error!("Could not open accounts file {}: {}", path.display(), e);
+                    exit(1);
+                }),
+                None => Accounts::in_memory(),
+            };
+            accounts.allow_guests(!require_login);
+            let tls = match (tls, require_tls) {
+                (false, false) => TlsMode::Off,
+                (true, false) => TlsMode::Optional(tls_acceptor(cert, key)),
+                (_, true) => TlsMode::Required(tls_acceptor(cert, key)),
+            };
+            info!("Starting Server");
+            let server = ChatServer::builder()
+                .bind(bind)
+                .discovery_port(discovery_port)
+                .history(history)
+                .accounts(accounts)
+                .queue(QueueConfig {
+                    capacity: queue_size as usize,
+                    policy: overflow,
+                })
+                .tls(tls)
+                .handle_signals(true)
+                .start()
+                .unwrap_or_else(|e| {
+                    error!("Could not bind server to {}: {}", bind, e);
+                    exit(1);
+                });
+            server.wait().unwrap();
+        }
+        Command::Connect { address, discovery_port, tls, encoding, username, register } => {
+            connect(&address, ConnectOptions { tls, discovery_port, encoding }, username, register, full_screen);
+        }
+        Command::Auto { port, discovery_port, tls, encoding, username, register } => {
+            let mut addr = quick_chat::discover(discovery_port);
+            // Kept until the client exits, or a signal stops it and then the whole process
+            let hosted = Arc::new(Mutex::new(None));
+            if addr.is_none() {
+                info!("Starting Server");
+                let bind = SocketAddr::from(([0, 0, 0, 0], port));
+                let mode = if tls {
+                    TlsMode::Optional(tls_acceptor(None, None))
+                } else {
+                    TlsMode::Off
+                };
+                *hosted.lock().unwrap() = Some(ChatServer::builder()
+                    .bind(bind)
+                    .discovery_port(discovery_port)
+                    .tls(mode)
+                    .start()
+                    .unwrap());
+                stop_on_signal(hosted.clone(), full_screen);
+
+                // sleep for 2 seconds
+                thread::sleep(std::time::Duration::from_secs(2));
+                addr = quick_chat::discover(discovery_port);
+                // check if addr is none
+                if addr.is_none() {
+                    error!("Could not find server");
+                    exit(1);
+                }
+            }
+            let server = addr.unwrap();
+            if tls && !server.tls {
+                error!("The server at {} does not offer TLS", server.address);
+                exit(1);
+            }
+            // Use TLS whenever the server offers it
+            let options = ConnectOptions {
+                tls: server.tls,
+                discovery_port,
+                encoding,
+            };
+            connect(&server.address.to_string(), options, username, register, full_screen);
+            // Held while shutting down, so a signal meanwhile waits for the clients to be told
+            let mut hosted = hosted.lock().unwrap();
+            if let Some(server) = hosted.take() {
+                if let Err(e) = server.shutdown() {
+                    error!("Server failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Left alone, Ctrl-C and SIGTERM would end the process before the hosted server told its clients
+// why, so they stop the server first and only then exit
+fn stop_on_signal(hosted: Arc<Mutex<Option<ChatServer>>>, full_screen: bool) {
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to listen for signals");
+        let signal = runtime.block_on(shutdown_signal());
+        info!("Stopping the server on {}", signal);
+        if let Some(server) = hosted.lock().unwrap().take() {
+            if let Err(e) = server.shutdown() {
+                error!("Server failed: {}", e);
+            }
+        }
+        if full_screen {
+            ratatui::restore();
+        }
+        exit(0);
+    });
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> &'static str {
+    tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    "Ctrl-C"
+}
+
+fn tls_acceptor(cert: Option<PathBuf>, key: Option<PathBuf>) -> tokio_rustls::TlsAcceptor {
+    quick_chat::tls_acceptor(cert, key).unwrap_or_else(|e| {
+        error!("Could not set up TLS: {}", e);
+        exit(1);
+    })
+}
+
+fn connect(address: &str, options: ConnectOptions, username: Option<String>, register: bool, full_screen: bool) {
+    info!("Connecting to server: {}", address);
+    let client = ChatClient::connect(address, options).unwrap_or_else(|e| {
+        error!("Could not connect to {}: {}", address, e);
+        exit(1);
+    });
+    // Subscribed before picking a name, so what was missed since last time is not
+    let events = client.subscribe();
+    if !terminal::pick_username(&client, username, register) {
+        client.close();
+        return;
+    }
+    if full_screen {
+        if let Err(e) = Tui::new(client).run(events) {
+            error!("Terminal failed: {}", e);
             exit(1);
         }
+    } else {
+        Terminal::new(client).run(events);
     }
-    info!(
-        "Connecting to server: {}", ip.unwrap()
-    );
-    let server_socket = TcpStream::connect((ip.unwrap(), 42069)).unwrap();
-    let mut client = client::Client::new(server_socket);
-    client.run();
 }
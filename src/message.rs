@@ -1,16 +1,183 @@
+use std::io;
+
 use chrono::{Local, TimeZone};
-use log::{debug, trace};
+use log::debug;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use uuid::Uuid;
 
+use crate::e2e::Envelope;
+use crate::encoding::Encoding;
+use crate::history::HistoryQuery;
 use crate::message_types::MessageType;
+use crate::presence::Presence;
+use crate::protocol::{AGENT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// The room everyone is in until they leave it.
+pub const DEFAULT_ROOM: &str = "#general";
+
+fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+fn default_encoding() -> String {
+    Encoding::Json.name().to_string()
+}
 
-#[derive(Serialize, Deserialize)]
-pub(crate) struct Message {
+/// One message on the wire: chat, a notice from the server or a request and its answer. Which of
+/// these it is, and what it carries, depends on its [`MessageType`].
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Message {
+    // Both are assigned by the server when it accepts a message, clients always send them zeroed
+    #[serde(default)]
+    id: Uuid,
+    #[serde(default)]
+    seq: u64,
+    #[serde(default)]
     username: String,
-    message: String,
     timestamp: i64,
-    type_: i32,
+    body: Body,
+}
+
+// What a message carries, tagged with its type on the wire. Each kind only has the fields it uses,
+// anything a newer peer sends that this build does not know turns into Unknown instead of an error.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub(crate) enum Body {
+    // First thing a client sends, the versions of the protocol it can speak and the encodings it
+    // would like, best first
+    Hello {
+        min_version: u32,
+        max_version: u32,
+        #[serde(default)]
+        agent: String,
+        #[serde(default)]
+        encodings: Vec<String>,
+    },
+    // The server's answer to Hello, with the version and encoding both sides will use
+    Welcome {
+        version: u32,
+        #[serde(default)]
+        agent: String,
+        #[serde(default = "default_encoding")]
+        encoding: String,
+    },
+    Ping,
+    Pong,
+    Message {
+        room: String,
+        text: String,
+    },
+    Action {
+        room: String,
+        text: String,
+    },
+    // The text is ciphertext when the envelope is set
+    DirectMessage {
+        recipient: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        envelope: Option<Envelope>,
+    },
+    Join {
+        room: String,
+    },
+    Leave {
+        room: String,
+    },
+    SetUsername,
+    UsernameTaken,
+    UsernameAvailable,
+    Login {
+        password: String,
+    },
+    Register {
+        password: String,
+    },
+    LoginRequired,
+    LoginFailed {
+        reason: String,
+    },
+    // The new name is the username
+    NickChanged {
+        old_username: String,
+    },
+    PublicKey {
+        key: String,
+    },
+    FetchMessages {
+        #[serde(default)]
+        query: HistoryQuery,
+    },
+    ClearToSend,
+    CreateRoom {
+        room: String,
+    },
+    JoinRoom {
+        room: String,
+    },
+    LeaveRoom {
+        room: String,
+    },
+    // Empty when asking, the rooms the server knows of in the answer
+    ListRooms {
+        #[serde(default)]
+        rooms: Vec<String>,
+    },
+    Who {
+        #[serde(default)]
+        users: Vec<Presence>,
+    },
+    UserList {
+        users: Vec<Presence>,
+    },
+    Error {
+        text: String,
+    },
+    ServerShutdown {
+        reason: String,
+    },
+    // The answer to a message of a type the receiver does not know, echoing its timestamp
+    UnknownMessage,
+    // Tells a sender the id and seq its message was stored under, echoing the timestamp it was sent with
+    Accepted,
+    #[serde(other)]
+    Unknown,
+}
+
+impl Body {
+    fn room(&self) -> Option<&String> {
+        match self {
+            Body::Message { room, .. } | Body::Action { room, .. } | Body::Join { room }
+            | Body::Leave { room } | Body::CreateRoom { room } | Body::JoinRoom { room }
+            | Body::LeaveRoom { room } => Some(room),
+            _ => None,
+        }
+    }
+
+    // Whatever plain text the message carries
+    fn text(&self) -> Option<&String> {
+        match self {
+            Body::Message { text, .. } | Body::Action { text, .. } | Body::DirectMessage { text, .. }
+            | Body::Error { text } => Some(text),
+            Body::Login { password } | Body::Register { password } => Some(password),
+            Body::LoginFailed { reason } | Body::ServerShutdown { reason } => Some(reason),
+            Body::NickChanged { old_username } => Some(old_username),
+            Body::PublicKey { key } => Some(key),
+            _ => None,
+        }
+    }
+
+    fn text_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Body::Message { text, .. } | Body::Action { text, .. } | Body::DirectMessage { text, .. }
+            | Body::Error { text } => Some(text),
+            Body::Login { password } | Body::Register { password } => Some(password),
+            Body::LoginFailed { reason } | Body::ServerShutdown { reason } => Some(reason),
+            Body::NickChanged { old_username } => Some(old_username),
+            Body::PublicKey { key } => Some(key),
+            _ => None,
+        }
+    }
 }
 
 impl Message {
@@ -18,105 +185,356 @@ impl Message {
         MessageBuilder::new()
     }
 
-    pub(crate) fn from_bytes(bytes: &[u8]) -> Vec<Message> {
-        let str_msg = String::from_utf8_lossy(&bytes);
-        trace!("Received messages: {}", str_msg);
-        let v: Value = serde_json::from_str(&str_msg).unwrap();
-        let mut messages = Vec::new();
-        if let Value::Array(msg_array) = v {
-            for msg in msg_array {
-                trace!("Received message: {}", msg);
-                messages.push(serde_json::from_str::<Message>(&*msg.to_string()).unwrap())
-            }
-        }
+    pub(crate) fn from_bytes(bytes: &[u8], encoding: Encoding) -> io::Result<Vec<Message>> {
+        let messages: Vec<Message> = match encoding {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MessagePack => rmp_serde::from_slice(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
         debug!("Received {} messages", messages.len());
-        messages
+        Ok(messages)
+    }
+
+    pub(crate) fn to_bytes(messages: &[Message], encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Json => serde_json::to_vec(messages).expect("Failed to serialize messages"),
+            // With field names, so fields can be added and left out the same way as in JSON
+            Encoding::MessagePack => rmp_serde::to_vec_named(messages).expect("Failed to serialize messages"),
+        }
+    }
+
+    // Replaces whatever the sender put in with the server's identity, ordering and clock
+    pub(crate) fn stamp(&mut self, seq: u64) {
+        self.assign_seq(seq);
+        self.timestamp = now_nanos();
+    }
+
+    pub(crate) fn assign_seq(&mut self, seq: u64) {
+        self.id = Uuid::new_v4();
+        self.seq = seq;
+    }
+
+    // Keeps the identity a message was stored with, e.g. for history written in the old format
+    pub(crate) fn restore_id(&mut self, id: Uuid, seq: u64) {
+        self.id = id;
+        self.seq = seq;
+    }
+
+    pub(crate) fn body(&self) -> &Body {
+        &self.body
+    }
+
+    /// Assigned by the server, unique across all messages it relayed.
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Assigned by the server, increases with every stored message.
+    pub fn get_seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Nanoseconds since the Unix epoch, by the server's clock for stored messages.
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Who sent it.
+    pub fn get_username(&self) -> String {
+        self.username.clone()
     }
 
-    pub(crate) fn to_string(&self) -> String {
-        match MessageType::from_int(self.type_) {
+    pub(crate) fn set_username(&mut self, username: &str) {
+        self.username = username.to_string();
+    }
+
+    /// The room it was said in or is about, [`DEFAULT_ROOM`] for messages not tied to one.
+    pub fn get_room(&self) -> String {
+        self.body.room().cloned().unwrap_or_else(default_room)
+    }
+
+    /// Who a direct message is for.
+    pub fn get_recipient(&self) -> Option<String> {
+        match &self.body {
+            Body::DirectMessage { recipient, .. } => Some(recipient.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_recipient(&mut self, recipient: &str) {
+        if let Body::DirectMessage { recipient: current, .. } = &mut self.body {
+            *current = recipient.to_string();
+        }
+    }
+
+    /// The users listed in answer to `who`.
+    pub fn get_users(&self) -> Vec<Presence> {
+        match &self.body {
+            Body::Who { users } | Body::UserList { users } => users.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The timestamp as local wall clock time, e.g. `09:41:07 AM`.
+    pub fn format_timestamp(&self) -> String {
+        let timestamp = self.get_timestamp();
+        let dt = Local.timestamp_nanos(timestamp);
+        dt.format("%I:%M:%S %p").to_string()
+    }
+
+    pub fn get_type(&self) -> MessageType {
+        match self.body {
+            Body::Hello { .. } => MessageType::Hello,
+            Body::Welcome { .. } => MessageType::Welcome,
+            Body::Ping => MessageType::Ping,
+            Body::Pong => MessageType::Pong,
+            Body::Message { .. } => MessageType::Message,
+            Body::Action { .. } => MessageType::Action,
+            Body::DirectMessage { .. } => MessageType::DirectMessage,
+            Body::Join { .. } => MessageType::Join,
+            Body::Leave { .. } => MessageType::Leave,
+            Body::SetUsername => MessageType::SetUsername,
+            Body::UsernameTaken => MessageType::UsernameTaken,
+            Body::UsernameAvailable => MessageType::UsernameAvailable,
+            Body::Login { .. } => MessageType::Login,
+            Body::Register { .. } => MessageType::Register,
+            Body::LoginRequired => MessageType::LoginRequired,
+            Body::LoginFailed { .. } => MessageType::LoginFailed,
+            Body::NickChanged { .. } => MessageType::NickChanged,
+            Body::PublicKey { .. } => MessageType::PublicKey,
+            Body::FetchMessages { .. } => MessageType::FetchMessages,
+            Body::ClearToSend => MessageType::ClearToSend,
+            Body::CreateRoom { .. } => MessageType::CreateRoom,
+            Body::JoinRoom { .. } => MessageType::JoinRoom,
+            Body::LeaveRoom { .. } => MessageType::LeaveRoom,
+            Body::ListRooms { .. } => MessageType::ListRooms,
+            Body::Who { .. } => MessageType::Who,
+            Body::UserList { .. } => MessageType::UserList,
+            Body::Error { .. } => MessageType::Error,
+            Body::ServerShutdown { .. } => MessageType::ServerShutdown,
+            Body::UnknownMessage => MessageType::UnknownMessage,
+            Body::Accepted => MessageType::Accepted,
+            Body::Unknown => MessageType::Unknown,
+        }
+    }
+
+    pub(crate) fn get_query(&self) -> HistoryQuery {
+        match &self.body {
+            Body::FetchMessages { query } => query.clone(),
+            _ => HistoryQuery::default(),
+        }
+    }
+
+    /// The text: what was said, or the details of a notice.
+    pub fn get_message(&self) -> String {
+        match &self.body {
+            Body::ListRooms { rooms } => rooms.join(", "),
+            _ => self.body.text().cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Replaces the text, e.g. from [`ServerHook::on_message`](crate::ServerHook::on_message).
+    /// Does nothing for messages that carry none.
+    pub fn set_message(&mut self, message: &str) {
+        if let Some(text) = self.body.text_mut() {
+            *text = message.to_string();
+        }
+    }
+
+    pub(crate) fn get_envelope(&self) -> Option<Envelope> {
+        match &self.body {
+            Body::DirectMessage { envelope, .. } => envelope.clone(),
+            _ => None,
+        }
+    }
+
+    // Swaps the ciphertext of an encrypted direct message for what it decrypted to
+    pub(crate) fn decrypted(&self, text: &str) -> Message {
+        let mut message = self.clone();
+        if let Body::DirectMessage { envelope, .. } = &mut message.body {
+            *envelope = None;
+        }
+        message.set_message(text);
+        message
+    }
+}
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.get_type() {
             MessageType::Ping => {
-                format!("Ping: {}",
-                        (Local::now() - Local.timestamp_nanos(self.timestamp)).num_milliseconds())
+                write!(f, "Ping: {}",
+                       (Local::now() - Local.timestamp_nanos(self.timestamp)).num_milliseconds())
+            }
+            // Pongs echo the ping's timestamp, so this is the full round trip
+            MessageType::Pong => {
+                write!(f, "[SERVER]: Pong, round trip {} ms",
+                       (Local::now() - Local.timestamp_nanos(self.timestamp)).num_milliseconds())
             }
             MessageType::Message => {
-                format!(
-                    "[{} @ {}]: {}",
+                write!(
+                    f,
+                    "{} [{} @ {}]: {}",
+                    self.get_room(),
+                    self.format_timestamp(),
+                    self.username,
+                    self.get_message()
+                )
+            }
+            MessageType::Action => {
+                write!(
+                    f,
+                    "{} * {} {}",
+                    self.get_room(),
+                    self.username,
+                    self.get_message()
+                )
+            }
+            MessageType::DirectMessage => {
+                write!(
+                    f,
+                    "*DM* [{} @ {} -> {}]: {}",
                     self.format_timestamp(),
                     self.username,
-                    self.message
+                    self.get_recipient().unwrap_or_default(),
+                    self.get_message()
                 )
             }
             MessageType::Join => {
-                format!(
-                    "[SERVER]: {} joined the chat",
-                    self.username
+                write!(
+                    f,
+                    "[SERVER]: {} joined {}",
+                    self.username,
+                    self.get_room()
                 )
             }
             MessageType::Leave => {
-                format!(
-                    "[SERVER]: {} left the chat",
+                write!(
+                    f,
+                    "[SERVER]: {} left {}",
+                    self.username,
+                    self.get_room()
+                )
+            }
+            MessageType::NickChanged => {
+                write!(
+                    f,
+                    "[SERVER]: {} is now known as {}",
+                    self.get_message(),
                     self.username
                 )
             }
             MessageType::SetUsername => {
-                format!(
+                write!(
+                    f,
                     "[SERVER]: username to {}",
                     self.username,
                 )
             }
             MessageType::UsernameAvailable => {
-                format!(
+                write!(
+                    f,
                     "[SERVER]: {} is available",
                     self.username
                 )
             }
             MessageType::UsernameTaken => {
-                format!(
+                write!(
+                    f,
                     "[SERVER]: {} is not available",
                     self.username
                 )
             }
+            // Login and Register carry a password and fall through to printing just their type
+            MessageType::LoginRequired => {
+                write!(
+                    f,
+                    "[SERVER]: {} is a registered account, log in to use it",
+                    self.username
+                )
+            }
+            MessageType::LoginFailed => {
+                write!(
+                    f,
+                    "[SERVER]: {}",
+                    self.get_message()
+                )
+            }
+            MessageType::CreateRoom => {
+                write!(
+                    f,
+                    "[SERVER]: {} created",
+                    self.get_room()
+                )
+            }
+            MessageType::JoinRoom => {
+                write!(
+                    f,
+                    "[SERVER]: Now talking in {}",
+                    self.get_room()
+                )
+            }
+            MessageType::LeaveRoom => {
+                write!(
+                    f,
+                    "[SERVER]: You left {}",
+                    self.get_room()
+                )
+            }
+            MessageType::ListRooms => {
+                write!(
+                    f,
+                    "[SERVER]: Rooms: {}",
+                    self.get_message()
+                )
+            }
+            MessageType::Error => {
+                write!(
+                    f,
+                    "[SERVER]: {}",
+                    self.get_message()
+                )
+            }
+            MessageType::ServerShutdown => {
+                write!(
+                    f,
+                    "[SERVER]: Shutting down: {}",
+                    self.get_message()
+                )
+            }
+            MessageType::Who | MessageType::UserList => {
+                let users = self.get_users();
+                write!(f, "[SERVER]: {} online", users.len())?;
+                for user in &users {
+                    write!(f, "\n  {}", user)?;
+                }
+                Ok(())
+            }
+            MessageType::UnknownMessage => {
+                write!(
+                    f,
+                    "[SERVER]: The server did not understand a message, it is probably running an older version"
+                )
+            }
             _ => {
-                format!("{}", MessageType::from_int(self.type_))
+                write!(f, "{}", self.get_type())
             }
         }
     }
-
-    fn get_timestamp(&self) -> i64 {
-        self.timestamp
-    }
-
-    pub(crate) fn get_username(&self) -> String {
-        self.username.clone()
-    }
-
-    pub(crate) fn format_timestamp(&self) -> String {
-        let timestamp = self.get_timestamp();
-        let dt = Local.timestamp_nanos(timestamp);
-        dt.format("%I:%M:%S %p").to_string()
-    }
-
-    pub(crate) fn get_type(&self) -> MessageType {
-        MessageType::from_int(self.type_)
-    }
-}
-
-impl Clone for Message {
-    fn clone(&self) -> Self {
-        Message {
-            username: self.username.clone(),
-            message: self.message.clone(),
-            timestamp: self.timestamp,
-            type_: self.type_,
-        }
-    }
 }
 
 pub(crate) struct MessageBuilder {
     username: String,
     message: String,
     type_: MessageType,
+    timestamp: Option<i64>,
+    room: String,
+    recipient: Option<String>,
+    users: Vec<Presence>,
+    rooms: Vec<String>,
+    query: Option<HistoryQuery>,
+    envelope: Option<Envelope>,
+    version: u32,
+    encoding: Encoding,
 }
 
 impl MessageBuilder {
@@ -125,16 +543,25 @@ impl MessageBuilder {
             username: String::new(),
             message: String::new(),
             type_: MessageType::Message,
+            timestamp: None,
+            room: default_room(),
+            recipient: None,
+            users: Vec::new(),
+            rooms: Vec::new(),
+            query: None,
+            envelope: None,
+            version: PROTOCOL_VERSION,
+            encoding: Encoding::Json,
         }
     }
 
-    pub(crate) fn username(&mut self, username: &String) -> &mut MessageBuilder {
-        self.username = username.clone();
+    pub(crate) fn username(&mut self, username: &str) -> &mut MessageBuilder {
+        self.username = username.to_string();
         self
     }
 
-    pub(crate) fn message(&mut self, message: &String) -> &mut MessageBuilder {
-        self.message = message.clone();
+    pub(crate) fn message(&mut self, message: &str) -> &mut MessageBuilder {
+        self.message = message.to_string();
         self
     }
 
@@ -143,15 +570,124 @@ impl MessageBuilder {
         self
     }
 
+    pub(crate) fn timestamp(&mut self, timestamp: i64) -> &mut MessageBuilder {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub(crate) fn room(&mut self, room: &str) -> &mut MessageBuilder {
+        self.room = room.to_string();
+        self
+    }
+
+    pub(crate) fn recipient(&mut self, recipient: &str) -> &mut MessageBuilder {
+        self.recipient = Some(recipient.to_string());
+        self
+    }
+
+    pub(crate) fn users(&mut self, users: Vec<Presence>) -> &mut MessageBuilder {
+        self.users = users;
+        self
+    }
+
+    pub(crate) fn rooms(&mut self, rooms: Vec<String>) -> &mut MessageBuilder {
+        self.rooms = rooms;
+        self
+    }
+
+    pub(crate) fn query(&mut self, query: HistoryQuery) -> &mut MessageBuilder {
+        self.query = Some(query);
+        self
+    }
+
+    pub(crate) fn envelope(&mut self, envelope: Envelope) -> &mut MessageBuilder {
+        self.envelope = Some(envelope);
+        self
+    }
+
+    // The version a Welcome settles on
+    pub(crate) fn version(&mut self, version: u32) -> &mut MessageBuilder {
+        self.version = version;
+        self
+    }
+
+    // The encoding a Hello asks for or a Welcome settles on
+    pub(crate) fn encoding(&mut self, encoding: Encoding) -> &mut MessageBuilder {
+        self.encoding = encoding;
+        self
+    }
+
     pub(crate) fn build(&self) -> Message {
         Message {
+            id: Uuid::nil(),
+            seq: 0,
             username: self.username.clone(),
-            message: self.message.clone(),
-            timestamp: {
-                let now = Local::now();
-                now.timestamp_nanos()
+            timestamp: self.timestamp.unwrap_or_else(now_nanos),
+            body: self.body(),
+        }
+    }
+
+    // The one asked for, then JSON as the fallback every server has
+    fn encodings(&self) -> Vec<String> {
+        let mut encodings = vec![self.encoding.name().to_string()];
+        if self.encoding != Encoding::Json {
+            encodings.push(Encoding::Json.name().to_string());
+        }
+        encodings
+    }
+
+    fn body(&self) -> Body {
+        let room = self.room.clone();
+        let text = self.message.clone();
+        match self.type_ {
+            MessageType::Hello => Body::Hello {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+                agent: AGENT.to_string(),
+                encodings: self.encodings(),
             },
-            type_: self.type_.as_int(),
+            MessageType::Welcome => Body::Welcome {
+                version: self.version,
+                agent: AGENT.to_string(),
+                encoding: self.encoding.name().to_string(),
+            },
+            MessageType::Ping => Body::Ping,
+            MessageType::Pong => Body::Pong,
+            MessageType::Message => Body::Message { room, text },
+            MessageType::Action => Body::Action { room, text },
+            MessageType::DirectMessage => Body::DirectMessage {
+                recipient: self.recipient.clone().unwrap_or_default(),
+                text,
+                envelope: self.envelope.clone(),
+            },
+            MessageType::Join => Body::Join { room },
+            MessageType::Leave => Body::Leave { room },
+            MessageType::SetUsername => Body::SetUsername,
+            MessageType::UsernameTaken => Body::UsernameTaken,
+            MessageType::UsernameAvailable => Body::UsernameAvailable,
+            MessageType::Login => Body::Login { password: text },
+            MessageType::Register => Body::Register { password: text },
+            MessageType::LoginRequired => Body::LoginRequired,
+            MessageType::LoginFailed => Body::LoginFailed { reason: text },
+            MessageType::NickChanged => Body::NickChanged { old_username: text },
+            MessageType::PublicKey => Body::PublicKey { key: text },
+            MessageType::FetchMessages => Body::FetchMessages { query: self.query.clone().unwrap_or_default() },
+            MessageType::ClearToSend => Body::ClearToSend,
+            MessageType::CreateRoom => Body::CreateRoom { room },
+            MessageType::JoinRoom => Body::JoinRoom { room },
+            MessageType::LeaveRoom => Body::LeaveRoom { room },
+            MessageType::ListRooms => Body::ListRooms { rooms: self.rooms.clone() },
+            MessageType::Who => Body::Who { users: self.users.clone() },
+            MessageType::UserList => Body::UserList { users: self.users.clone() },
+            MessageType::Error => Body::Error { text },
+            MessageType::ServerShutdown => Body::ServerShutdown { reason: text },
+            MessageType::UnknownMessage => Body::UnknownMessage,
+            MessageType::Accepted => Body::Accepted,
+            MessageType::Unknown => Body::Unknown,
         }
     }
-}
\ No newline at end of file
+}
+
+pub(crate) fn now_nanos() -> i64 {
+    Local::now().timestamp_nanos_opt().unwrap_or_default()
+}
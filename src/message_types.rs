@@ -1,4 +1,8 @@
-pub(crate) enum MessageType {
+/// What a [`Message`](crate::Message) is.
+#[derive(PartialEq, Eq, Debug)]
+pub enum MessageType {
+    Hello,
+    Welcome,
     Ping,
     Join,
     Leave,
@@ -7,12 +11,37 @@ pub(crate) enum MessageType {
     UsernameAvailable,
     FetchMessages,
     ClearToSend,
+    CreateRoom,
+    JoinRoom,
+    LeaveRoom,
+    ListRooms,
+    Error,
+    DirectMessage,
+    Action,
+    Who,
+    UserList,
+    ServerShutdown,
+    Pong,
+    PublicKey,
+    Login,
+    Register,
+    LoginRequired,
+    LoginFailed,
+    NickChanged,
     Message,
+    /// The answer to a message of a type the other side does not know.
+    UnknownMessage,
+    /// The server stored what its sender sent, under the id and sequence number this carries.
+    Accepted,
+    /// A message of a type this version does not know, sent by a newer peer.
+    Unknown,
 }
 
 impl MessageType {
-    pub(crate) fn as_int(&self) -> i32 {
-        match self {
+    // The numbers the protocol used before types were tagged by name, still read from old history
+    // files and spoken to clients that predate the handshake. Types added since have none.
+    pub(crate) fn legacy_code(&self) -> Option<i32> {
+        let code = match self {
             MessageType::Ping => { 0 }
             MessageType::Join => { 1 }
             MessageType::Leave => { 2 }
@@ -21,11 +50,30 @@ impl MessageType {
             MessageType::UsernameAvailable => { 5 }
             MessageType::FetchMessages => { 6 }
             MessageType::ClearToSend => { 7 }
+            MessageType::CreateRoom => { 8 }
+            MessageType::JoinRoom => { 9 }
+            MessageType::LeaveRoom => { 10 }
+            MessageType::ListRooms => { 11 }
+            MessageType::Error => { 12 }
+            MessageType::DirectMessage => { 13 }
+            MessageType::Action => { 14 }
+            MessageType::Who => { 15 }
+            MessageType::UserList => { 16 }
+            MessageType::ServerShutdown => { 17 }
+            MessageType::Pong => { 18 }
+            MessageType::PublicKey => { 19 }
+            MessageType::Login => { 20 }
+            MessageType::Register => { 21 }
+            MessageType::LoginRequired => { 22 }
+            MessageType::LoginFailed => { 23 }
+            MessageType::NickChanged => { 24 }
             MessageType::Message => { 32 }
-        }
+            _ => return None,
+        };
+        Some(code)
     }
-    pub(crate) fn from_int(i: i32) -> MessageType {
-        match i {
+    pub(crate) fn from_legacy_code(code: i32) -> Option<MessageType> {
+        let type_ = match code {
             0 => { MessageType::Ping }
             1 => { MessageType::Join }
             2 => { MessageType::Leave }
@@ -34,13 +82,33 @@ impl MessageType {
             5 => { MessageType::UsernameAvailable }
             6 => { MessageType::FetchMessages }
             7 => { MessageType::ClearToSend }
+            8 => { MessageType::CreateRoom }
+            9 => { MessageType::JoinRoom }
+            10 => { MessageType::LeaveRoom }
+            11 => { MessageType::ListRooms }
+            12 => { MessageType::Error }
+            13 => { MessageType::DirectMessage }
+            14 => { MessageType::Action }
+            15 => { MessageType::Who }
+            16 => { MessageType::UserList }
+            17 => { MessageType::ServerShutdown }
+            18 => { MessageType::Pong }
+            19 => { MessageType::PublicKey }
+            20 => { MessageType::Login }
+            21 => { MessageType::Register }
+            22 => { MessageType::LoginRequired }
+            23 => { MessageType::LoginFailed }
+            24 => { MessageType::NickChanged }
             32 => { MessageType::Message }
-            _ => { MessageType::Message }
-        }
+            _ => return None,
+        };
+        Some(type_)
     }
 
     fn as_str(&self) -> String {
         match self {
+            MessageType::Hello => { "Hello".to_string() }
+            MessageType::Welcome => { "Welcome".to_string() }
             MessageType::Ping => { "Ping".to_string() }
             MessageType::Join => { "Join".to_string() }
             MessageType::Leave => { "Leave".to_string() }
@@ -49,7 +117,27 @@ impl MessageType {
             MessageType::UsernameAvailable => { "UsernameAvailable".to_string() }
             MessageType::FetchMessages => { "FetchMessages".to_string() }
             MessageType::ClearToSend => { "ClearToSend".to_string() }
+            MessageType::CreateRoom => { "CreateRoom".to_string() }
+            MessageType::JoinRoom => { "JoinRoom".to_string() }
+            MessageType::LeaveRoom => { "LeaveRoom".to_string() }
+            MessageType::ListRooms => { "ListRooms".to_string() }
+            MessageType::Error => { "Error".to_string() }
+            MessageType::DirectMessage => { "DirectMessage".to_string() }
+            MessageType::Action => { "Action".to_string() }
+            MessageType::Who => { "Who".to_string() }
+            MessageType::UserList => { "UserList".to_string() }
+            MessageType::ServerShutdown => { "ServerShutdown".to_string() }
+            MessageType::Pong => { "Pong".to_string() }
+            MessageType::PublicKey => { "PublicKey".to_string() }
+            MessageType::Login => { "Login".to_string() }
+            MessageType::Register => { "Register".to_string() }
+            MessageType::LoginRequired => { "LoginRequired".to_string() }
+            MessageType::LoginFailed => { "LoginFailed".to_string() }
+            MessageType::NickChanged => { "NickChanged".to_string() }
             MessageType::Message => { "Message".to_string() }
+            MessageType::UnknownMessage => { "UnknownMessage".to_string() }
+            MessageType::Accepted => { "Accepted".to_string() }
+            MessageType::Unknown => { "Unknown".to_string() }
         }
     }
 }
@@ -59,9 +147,3 @@ impl std::fmt::Display for MessageType {
         write!(f, "{}", self.as_str())
     }
 }
-
-impl PartialEq for MessageType {
-    fn eq(&self, other: &Self) -> bool {
-        self.as_int() == other.as_int()
-    }
-}
\ No newline at end of file
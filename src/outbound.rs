@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use log::{debug, warn};
+use tokio::sync::{Notify, watch};
+
+use crate::encoding::Encoding;
+use crate::message::Message;
+
+/// Frames a client may have waiting to be sent unless configured otherwise.
+pub const DEFAULT_QUEUE_SIZE: u32 = 256;
+// How long a full queue may hold up delivery under OverflowPolicy::Block before the client is evicted
+const BLOCK_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// What the server does with a client whose outbound queue is full.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Throw away the oldest queued frame to make room
+    DropOldest,
+    /// Evict the client, it can reconnect and fetch what it missed
+    #[default]
+    Disconnect,
+    /// Wait briefly for the client to catch up, then evict it
+    Block,
+}
+
+/// Limits on what a server buffers for each client.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    /// Frames that may be waiting before `policy` kicks in.
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: DEFAULT_QUEUE_SIZE as usize,
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+// One frame's worth of messages, queued for any number of clients. It is encoded the first time a
+// client that speaks a given encoding writes it and that copy is shared with all the others.
+pub(crate) struct SharedFrame {
+    messages: Vec<Message>,
+    json: OnceLock<Vec<u8>>,
+    message_pack: OnceLock<Vec<u8>>,
+}
+
+impl SharedFrame {
+    pub(crate) fn new(messages: Vec<Message>) -> Arc<SharedFrame> {
+        Arc::new(SharedFrame {
+            messages,
+            json: OnceLock::new(),
+            message_pack: OnceLock::new(),
+        })
+    }
+
+    pub(crate) fn bytes(&self, encoding: Encoding) -> &[u8] {
+        let encoded = match encoding {
+            Encoding::Json => &self.json,
+            Encoding::MessagePack => &self.message_pack,
+        };
+        encoded.get_or_init(|| Message::to_bytes(&self.messages, encoding))
+    }
+}
+
+pub(crate) struct QueueStats {
+    pub(crate) depth: usize,
+    pub(crate) high_water: usize,
+    pub(crate) dropped: u64,
+}
+
+// Frames waiting to be written to one client. Producers never wait on the socket, only on this
+// queue, and only when the policy is Block.
+pub(crate) struct OutboundQueue {
+    frames: Mutex<VecDeque<Arc<SharedFrame>>>,
+    config: QueueConfig,
+    // Wakes the writer when a frame arrives
+    ready: Notify,
+    // Wakes a blocked producer when the writer takes a frame
+    space: Notify,
+    closed: watch::Sender<bool>,
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+    client_name: String,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(config: QueueConfig, client_name: String) -> OutboundQueue {
+        OutboundQueue {
+            frames: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            config,
+            ready: Notify::new(),
+            space: Notify::new(),
+            closed: watch::Sender::new(false),
+            high_water: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            client_name,
+        }
+    }
+
+    // Queues without waiting, so under Block a full queue evicts the client straight away
+    pub(crate) fn try_push(&self, frame: Arc<SharedFrame>) -> bool {
+        match self.enqueue(frame) {
+            Ok(()) => true,
+            Err(_) => {
+                self.evict();
+                false
+            }
+        }
+    }
+
+    pub(crate) async fn push(&self, frame: Arc<SharedFrame>) -> bool {
+        let mut frame = match self.enqueue(frame) {
+            Ok(()) => return true,
+            Err(frame) => frame,
+        };
+        if self.config.policy == OverflowPolicy::Block {
+            let deadline = tokio::time::Instant::now() + BLOCK_TIMEOUT;
+            while tokio::time::timeout_at(deadline, self.space.notified()).await.is_ok() {
+                frame = match self.enqueue(frame) {
+                    Ok(()) => return true,
+                    Err(frame) => frame,
+                };
+            }
+        }
+        self.evict();
+        false
+    }
+
+    // Hands the frame back if it could not be queued and the client should be evicted
+    fn enqueue(&self, frame: Arc<SharedFrame>) -> Result<(), Arc<SharedFrame>> {
+        if self.is_closed() {
+            return Err(frame);
+        }
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() >= self.config.capacity {
+            match self.config.policy {
+                OverflowPolicy::DropOldest => {
+                    frames.pop_front();
+                    if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                        warn!("Outbound queue for {} is full, dropping its oldest messages", self.client_name);
+                    }
+                }
+                OverflowPolicy::Disconnect | OverflowPolicy::Block => return Err(frame),
+            }
+        }
+        frames.push_back(frame);
+        self.high_water.fetch_max(frames.len(), Ordering::Relaxed);
+        drop(frames);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    // Nothing else gets written to an evicted client, it will never read it anyway
+    fn evict(&self) {
+        if !self.is_closed() {
+            warn!("Evicting {}, its outbound queue stayed full", self.client_name);
+            self.frames.lock().unwrap().clear();
+            self.close();
+        }
+    }
+
+    // Waits for the next frame. Whatever was queued before the queue closed is still handed out,
+    // after that it returns None.
+    pub(crate) async fn pop(&self) -> Option<Arc<SharedFrame>> {
+        let mut closed = self.closed.subscribe();
+        loop {
+            if let Some(frame) = self.frames.lock().unwrap().pop_front() {
+                self.space.notify_one();
+                return Some(frame);
+            }
+            if *closed.borrow_and_update() {
+                return None;
+            }
+            tokio::select! {
+                _ = self.ready.notified() => {}
+                _ = closed.changed() => {}
+            }
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        debug!("Closing outbound queue for {}", self.client_name);
+        self.closed.send_replace(true);
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    pub(crate) async fn closed(&self) {
+        let _ = self.closed.subscribe().wait_for(|closed| *closed).await;
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.frames.lock().unwrap().len(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(capacity: usize, policy: OverflowPolicy) -> OutboundQueue {
+        OutboundQueue::new(QueueConfig { capacity, policy }, "test".to_string())
+    }
+
+    fn frame(text: &str) -> Arc<SharedFrame> {
+        SharedFrame::new(vec![Message::builder().message(text).build()])
+    }
+
+    async fn pop_text(queue: &OutboundQueue) -> Option<String> {
+        queue.pop().await.map(|frame| frame.messages[0].get_message())
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let queue = queue(2, OverflowPolicy::DropOldest);
+        for text in ["first", "second", "third"] {
+            assert!(queue.try_push(frame(text)));
+        }
+        assert_eq!(queue.stats().dropped, 1);
+        assert_eq!(queue.stats().high_water, 2);
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("second"));
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("third"));
+        assert!(!queue.is_closed());
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_full_queue() {
+        let queue = queue(1, OverflowPolicy::Disconnect);
+        assert!(queue.try_push(frame("first")));
+        assert!(!queue.push(frame("second")).await);
+        assert!(queue.is_closed());
+        // What was queued is thrown away with it, and nothing more gets in
+        assert_eq!(pop_text(&queue).await, None);
+        assert!(!queue.try_push(frame("third")));
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_the_writer() {
+        let queue = queue(1, OverflowPolicy::Block);
+        assert!(queue.try_push(frame("first")));
+        let (pushed, popped) = tokio::join!(queue.push(frame("second")), async {
+            tokio::time::sleep(BLOCK_TIMEOUT / 5).await;
+            pop_text(&queue).await
+        });
+        assert!(pushed);
+        assert_eq!(popped.as_deref(), Some("first"));
+        assert_eq!(pop_text(&queue).await.as_deref(), Some("second"));
+        assert!(!queue.is_closed());
+    }
+
+    #[tokio::test]
+    async fn block_evicts_after_timeout() {
+        let queue = queue(1, OverflowPolicy::Block);
+        assert!(queue.try_push(frame("first")));
+        let started = tokio::time::Instant::now();
+        assert!(!queue.push(frame("second")).await);
+        assert!(started.elapsed() >= BLOCK_TIMEOUT);
+        assert!(queue.is_closed());
+    }
+}
@@ -0,0 +1,35 @@
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+/// Someone who is online.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Presence {
+    pub username: String,
+    /// Server time in nanoseconds.
+    pub connected_at: i64,
+    /// Seconds since they last said anything.
+    pub idle_secs: u64,
+    /// Base64 X25519 key that direct messages to this user get encrypted for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+impl std::fmt::Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<20} online since {}, idle {}",
+            self.username,
+            Local.timestamp_nanos(self.connected_at).format("%I:%M %p"),
+            format_duration(self.idle_secs)
+        )
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
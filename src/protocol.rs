@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+/// Newest version of the wire protocol this build speaks. Client and server agree on one when
+/// connecting, each saying which versions it can handle.
+pub const PROTOCOL_VERSION: u32 = 2;
+// Oldest version this build still speaks. Version 1 is the format from before the handshake, whose
+// clients are only told to upgrade.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 2;
+// Sent along with the handshake so either side can log who it is talking to
+pub(crate) const AGENT: &str = concat!("quick_chat/", env!("CARGO_PKG_VERSION"));
+// How long either side waits for the other's half of the handshake
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The newest version both sides speak, if their ranges overlap at all
+pub(crate) fn negotiate(min_version: u32, max_version: u32) -> Option<u32> {
+    let version = max_version.min(PROTOCOL_VERSION);
+    (version >= min_version.max(MIN_PROTOCOL_VERSION)).then_some(version)
+}
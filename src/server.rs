@@ -1,82 +1,564 @@
-use std::collections::VecDeque;
-use std::net::TcpListener;
+use std::collections::{BTreeSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::thread::{sleep, spawn};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
-use lazy_static::lazy_static;
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
+use tokio::{runtime, signal};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, interval, timeout, timeout_at};
+use tokio_rustls::TlsAcceptor;
 
-use crate::client_handler::ClientHandler;
+use crate::{DEFAULT_DISCOVERY_PORT, DEFAULT_SERVER_PORT};
+use crate::accounts::Accounts;
+use crate::client_handler;
+use crate::client_handler::{Broadcast, ClientHandler, ReadHalf, WriteHalf};
+use crate::encoding::Encoding;
+use crate::frame::{AsyncFrameReader, write_frame_async};
+use crate::history::{History, MemoryHistory};
+use crate::hooks::{ClientInfo, ServerHook};
+use crate::legacy;
+use crate::message::{Body, DEFAULT_ROOM, Message};
+use crate::message_types::MessageType;
+use crate::outbound::{OutboundQueue, QueueConfig, SharedFrame};
+use crate::protocol;
+use crate::protocol::{HANDSHAKE_TIMEOUT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use crate::server_discovery_thread::DiscoveryThread;
+use crate::tls::HANDSHAKE_BYTE;
 
-const SERVER_PORT: u16 = 42069;
+const QUEUE_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+// How long clients get to receive the shutdown notice before their connections are cut
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-type ClientHandlers = Arc<Mutex<VecDeque<ClientHandler>>>;
+/// Whether a server accepts TLS connections.
+#[derive(Clone)]
+pub enum TlsMode {
+    Off,
+    /// Both TLS and plaintext clients are accepted.
+    Optional(TlsAcceptor),
+    Required(TlsAcceptor),
+}
 
-lazy_static! {
-    pub(crate) static ref CLIENT_HANDLERS: ClientHandlers = Arc::new(Mutex::new(VecDeque::new()));
+// Everything one server knows, shared by its tasks and client handlers. Nothing here is global, so
+// several servers can run in one process.
+pub(crate) struct ServerState {
+    pub(crate) clients: Mutex<VecDeque<ClientHandler>>,
+    pub(crate) rooms: Mutex<BTreeSet<String>>,
+    pub(crate) history: Mutex<Box<dyn History>>,
+    pub(crate) accounts: Mutex<Accounts>,
+    pub(crate) hooks: Vec<Box<dyn ServerHook>>,
 }
 
-pub struct Server {
-    server_socket: TcpListener,
+impl ServerState {
+    pub(crate) fn remove_client(&self, client_name: &str) {
+        trace!("Removing client {}", client_name);
+        let mut client_handlers = self.clients.lock().unwrap();
+        if let Some(index) = client_handlers.iter().position(|client| client.client_name == client_name) {
+            trace!("Removed client: {}", client_handlers[index]);
+            client_handlers.remove(index);
+        }
+    }
 }
 
-impl Server {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let server_socket = TcpListener::bind(("0.0.0.0", SERVER_PORT))?;
-        // Bind to all interfaces
-        debug!("Server listening on: {:?}", server_socket.local_addr().unwrap());
-        Ok(Self {
-            server_socket,
-        })
+/// Sets up a [`ChatServer`]. Everything has a default, so
+/// `ChatServer::builder().start()` hosts one like `quick_chat serve` does.
+///
+/// ```no_run
+/// use quick_chat::ChatServer;
+///
+/// let server = ChatServer::builder()
+///     .bind("127.0.0.1:0".parse().unwrap())
+///     .disable_discovery()
+///     .start()?;
+/// println!("Listening on {}", server.local_addr());
+/// server.shutdown()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct ChatServerBuilder {
+    bind: SocketAddr,
+    discovery_port: Option<u16>,
+    history: Option<Box<dyn History>>,
+    accounts: Option<Accounts>,
+    queue: QueueConfig,
+    tls: TlsMode,
+    hooks: Vec<Box<dyn ServerHook>>,
+    handle_signals: bool,
+}
+
+impl ChatServerBuilder {
+    fn new() -> ChatServerBuilder {
+        ChatServerBuilder {
+            bind: SocketAddr::from(([0, 0, 0, 0], DEFAULT_SERVER_PORT)),
+            discovery_port: Some(DEFAULT_DISCOVERY_PORT),
+            history: None,
+            accounts: None,
+            queue: QueueConfig::default(),
+            tls: TlsMode::Off,
+            hooks: Vec::new(),
+            handle_signals: false,
+        }
+    }
+
+    /// Address to accept chat connections on, `0.0.0.0:42069` by default. Port 0 picks a free
+    /// one, see [`ChatServer::local_addr`].
+    pub fn bind(&mut self, bind: SocketAddr) -> &mut ChatServerBuilder {
+        self.bind = bind;
+        self
+    }
+
+    /// UDP port to answer discovery broadcasts on, 8888 by default.
+    pub fn discovery_port(&mut self, discovery_port: u16) -> &mut ChatServerBuilder {
+        self.discovery_port = Some(discovery_port);
+        self
     }
 
-    pub fn run(self) -> Result<(), std::io::Error> {
-        // start discovery thread
-        let discovery_thread = DiscoveryThread::new().unwrap();
-        spawn(move || {
-            discovery_thread.run();
+    /// Don't answer discovery broadcasts, clients have to be given the address.
+    pub fn disable_discovery(&mut self) -> &mut ChatServerBuilder {
+        self.discovery_port = None;
+        self
+    }
+
+    /// Where to keep history, in memory by default.
+    pub fn history(&mut self, history: Box<dyn History>) -> &mut ChatServerBuilder {
+        self.history = Some(history);
+        self
+    }
+
+    /// Registered accounts, none and guests allowed by default.
+    pub fn accounts(&mut self, accounts: Accounts) -> &mut ChatServerBuilder {
+        self.accounts = Some(accounts);
+        self
+    }
+
+    pub fn queue(&mut self, queue: QueueConfig) -> &mut ChatServerBuilder {
+        self.queue = queue;
+        self
+    }
+
+    pub fn tls(&mut self, tls: TlsMode) -> &mut ChatServerBuilder {
+        self.tls = tls;
+        self
+    }
+
+    /// Adds a hook, run after any added before it.
+    pub fn hook(&mut self, hook: impl ServerHook + 'static) -> &mut ChatServerBuilder {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Shut down on Ctrl-C or SIGTERM, as the `quick_chat` binary does. Off by default so an
+    /// embedding program keeps control of its signals.
+    pub fn handle_signals(&mut self, handle_signals: bool) -> &mut ChatServerBuilder {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Binds the sockets and starts serving on a thread of its own. History and accounts are
+    /// handed to the server, so the builder falls back to the defaults for them afterwards.
+    pub fn start(&mut self) -> io::Result<ChatServer> {
+        let listener = TcpListener::bind(self.bind)?;
+        let local_addr = listener.local_addr()?;
+        debug!("Server listening on: {:?}", local_addr);
+        let tls_offered = !matches!(self.tls, TlsMode::Off);
+        let discovery = match self.discovery_port {
+            Some(port) => Some(DiscoveryThread::new(port, local_addr.port(), tls_offered)?),
+            None => None,
+        };
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("Server Worker")
+            .build()?;
+
+        let history = self.history.take().unwrap_or_else(|| Box::new(MemoryHistory::new()));
+        let mut rooms = history.rooms();
+        rooms.insert(DEFAULT_ROOM.to_string());
+        debug!("Starting with rooms {:?}", rooms);
+        let state = Arc::new(ServerState {
+            clients: Mutex::new(VecDeque::new()),
+            rooms: Mutex::new(rooms),
+            history: Mutex::new(history),
+            accounts: Mutex::new(self.accounts.take().unwrap_or_else(Accounts::in_memory)),
+            hooks: std::mem::take(&mut self.hooks),
         });
-        trace!("Discovery thread started");
-
-        for client_socket in self.server_socket.incoming() {
-            match client_socket {
-                Ok(client_socket) => unsafe {
-                    debug!("New connection: {}", client_socket.peer_addr().unwrap());
-                    let client_handler = ClientHandler::new(client_socket);
-                    trace!("New client handler {} created", client_handler);
-                    CLIENT_HANDLERS.lock().unwrap().push_back(client_handler.clone());
-                    trace!("Client handler added to CLIENT_HANDLERS");
-                    thread::Builder::new()
-                        .name("ClientHandler Thread ".to_string()
-                            + &client_handler.client_name)
-                        .spawn(move || {
-                            trace!("ClientHandler thread started");
-                            client_handler.run();
-                        })
-                        .expect("Failed to spawn ClientHandler thread");
+        let (stop, stopped) = watch::channel(false);
+        let discovery_stop = Arc::new(AtomicBool::new(false));
+        if let Some(discovery) = discovery {
+            let discovery_stop = discovery_stop.clone();
+            thread::spawn(move || discovery.run(&discovery_stop));
+            trace!("Discovery thread started");
+        }
+        let acceptor = Acceptor {
+            tls: self.tls.clone(),
+            queue: self.queue,
+            handle_signals: self.handle_signals,
+        };
+        let thread = thread::Builder::new()
+            .name("Server".to_string())
+            .spawn(move || {
+                let result = runtime.block_on(acceptor.accept_clients(listener, state.clone(), stopped));
+                discovery_stop.store(true, Ordering::Relaxed);
+                // Handlers left over from aborted connections hold on to the state, let it go
+                state.clients.lock().unwrap().clear();
+                result
+            })?;
+        Ok(ChatServer {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running server, from [`ChatServerBuilder::start`]. Dropping it shuts the server down.
+pub struct ChatServer {
+    local_addr: SocketAddr,
+    stop: watch::Sender<bool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::new()
+    }
+
+    /// The address clients connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Tells every client the server is going away, gives them a few seconds to get the notice
+    /// and waits for the server to stop.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.stop.send_replace(true);
+        self.wait()
+    }
+
+    /// Blocks until the server stops, e.g. on a signal when
+    /// [`handle_signals`](ChatServerBuilder::handle_signals) is set.
+    pub fn wait(mut self) -> io::Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join()
+                .unwrap_or_else(|_| Err(io::Error::other("Server thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ChatServer {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.send_replace(true);
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Acceptor {
+    tls: TlsMode,
+    queue: QueueConfig,
+    handle_signals: bool,
+}
+
+impl Acceptor {
+    async fn accept_clients(self,
+                            listener: TcpListener,
+                            state: Arc<ServerState>,
+                            stopped: watch::Receiver<bool>) -> Result<(), std::io::Error> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+
+        let (broadcasts, broadcast_queue) = mpsc::unbounded_channel();
+        tokio::spawn(fan_out(broadcast_queue, state.clone()));
+        trace!("Broadcast task started");
+        tokio::spawn(report_queues(state.clone()));
+
+        let mut tasks = JoinSet::new();
+        let shutdown = shutdown_requested(stopped, self.handle_signals);
+        tokio::pin!(shutdown);
+        let reason = loop {
+            let accepted = tokio::select! {
+                reason = &mut shutdown => break reason,
+                accepted = listener.accept() => accepted,
+            };
+            // Forget about connections that have already finished
+            while tasks.try_join_next().is_some() {}
+            match accepted {
+                Ok((client_socket, addr)) => {
+                    debug!("New connection: {}", addr);
+                    // Frames are small and written whole, holding them back only adds latency
+                    if let Err(e) = client_socket.set_nodelay(true) {
+                        debug!("Failed to disable Nagle's algorithm for {}: {}", addr, e);
+                    }
+                    tasks.spawn(serve_client(client_socket, addr, self.tls.clone(), self.queue, broadcasts.clone(),
+                                             state.clone()));
                 }
                 Err(e) => {
                     error!("Error: {}", e);
-                    sleep(Duration::from_secs(1));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
                 }
             }
-        }
+        };
 
+        info!("Shutting down: {}", reason);
+        drop(listener);
+        notify_shutdown(&state, &reason);
+        if timeout(SHUTDOWN_GRACE, async { while tasks.join_next().await.is_some() {} }).await.is_err() {
+            warn!("{} connections did not close in time, aborting them", tasks.len());
+            tasks.shutdown().await;
+        }
+        if let Err(e) = state.history.lock().unwrap().flush() {
+            error!("Failed to flush history: {}", e);
+        }
+        info!("Server stopped");
         Ok(())
     }
 }
 
-pub fn remove_client(client_name: &String) {
-    trace!("Removing client {}", client_name);
-    let mut client_handlers = CLIENT_HANDLERS.lock().unwrap();
-    for (index, client) in client_handlers.iter_mut().enumerate() {
-        if client.client_name.eq(client_name) {
-            trace!("Removed client: {}", client_handlers[index]);
-            client_handlers.remove(index);
-            break;
+// Resolves with the reason clients are given once the handle or, if wanted, a signal asks to stop
+async fn shutdown_requested(mut stopped: watch::Receiver<bool>, handle_signals: bool) -> String {
+    let signal = async {
+        if handle_signals {
+            shutdown_signal().await
+        } else {
+            std::future::pending().await
+        }
+    };
+    tokio::select! {
+        signal = signal => format!("Server stopped by {}", signal),
+        _ = stopped.wait_for(|stopped| *stopped) => "Server stopped".to_string(),
+    }
+}
+
+async fn serve_client(client_socket: TcpStream,
+                      addr: SocketAddr,
+                      tls: TlsMode,
+                      queue: QueueConfig,
+                      broadcasts: UnboundedSender<Broadcast>,
+                      state: Arc<ServerState>) {
+    let (reader, mut writer, is_tls) = match negotiate(client_socket, &tls).await {
+        Ok(halves) => halves,
+        Err(e) => {
+            debug!("Dropping connection from {}: {}", addr, e);
+            return;
+        }
+    };
+    let client = ClientInfo {
+        address: addr,
+        username: String::new(),
+        tls: is_tls,
+    };
+    if !state.hooks.iter().all(|hook| hook.on_connect(&client)) {
+        debug!("Hook turned away connection from {}", addr);
+        let refusal = Message::builder()
+            .message("Connection refused by the server")
+            .message_type(MessageType::Error)
+            .build();
+        if write_frame_async(&mut writer, &Message::to_bytes(&[refusal], Encoding::Json)).await.is_ok() {
+            let _ = writer.shutdown().await;
+        }
+        return;
+    }
+    let mut frame_reader = AsyncFrameReader::new(reader);
+    let encoding = match greet(&mut frame_reader, &mut writer).await {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            debug!("Dropping connection from {}: {}", addr, e);
+            return;
+        }
+    };
+    let outbound = Arc::new(OutboundQueue::new(queue, addr.to_string()));
+    let client_handler = ClientHandler::new(addr, is_tls, encoding, outbound.clone(), broadcasts, state.clone());
+    trace!("New client handler {} created", client_handler);
+    state.clients.lock().unwrap().push_back(client_handler.clone());
+    trace!("Client handler added to the client list");
+    tokio::join!(
+        client_handler::write_outbound(writer, encoding, outbound, addr.to_string()),
+        client_handler.run(frame_reader),
+    );
+}
+
+// Waits for the client's Hello and answers with the protocol version and encoding they will both
+// use. Clients from before the handshake either say nothing until a username is picked or start
+// straight away in the old format, those are told to upgrade in the only format they can read.
+async fn greet(frame_reader: &mut AsyncFrameReader<ReadHalf>, writer: &mut WriteHalf) -> io::Result<Encoding> {
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let first = match timeout_at(deadline, frame_reader.peek()).await {
+        Ok(first) => first?.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed before the handshake"))?,
+        Err(_) => return refuse_legacy(writer, "no hello from the client").await,
+    };
+    // Clients from before frames were length-prefixed send bare JSON, which no frame length can
+    // start with, and read whatever arrives as JSON too
+    if first == b'[' {
+        writer.write_all(&legacy::upgrade_notice()).await?;
+        writer.shutdown().await?;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the client predates framing"));
+    }
+    let frame = match timeout_at(deadline, frame_reader.read_frame()).await {
+        Ok(frame) => frame?.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "closed before the handshake"))?,
+        Err(_) => return refuse_legacy(writer, "no hello from the client").await,
+    };
+    if legacy::is_legacy_frame(&frame) {
+        return refuse_legacy(writer, "the client predates the protocol handshake").await;
+    }
+    let hello = Message::from_bytes(&frame, Encoding::Json).ok().and_then(|messages| messages.into_iter().next());
+    let answer = match hello.as_ref().map(Message::body) {
+        Some(Body::Hello { min_version, max_version, agent, encodings }) => {
+            match protocol::negotiate(*min_version, *max_version) {
+                Some(version) => {
+                    // Clients that did not say get JSON, which every version speaks
+                    let encoding = encodings.iter()
+                        .find_map(|name| Encoding::from_name(name))
+                        .unwrap_or(Encoding::Json);
+                    debug!("Client {} speaks protocol version {} in {}", agent, version, encoding);
+                    Ok((version, encoding))
+                }
+                None => Err(format!("This server speaks protocol versions {} to {}, the client {} to {}",
+                                    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, min_version, max_version)),
+            }
+        }
+        _ => Err("Expected a hello to start the connection".to_string()),
+    };
+    let reply = match &answer {
+        Ok((version, encoding)) => Message::builder()
+            .message_type(MessageType::Welcome)
+            .version(*version)
+            .encoding(*encoding)
+            .build(),
+        Err(e) => Message::builder()
+            .message(e)
+            .message_type(MessageType::Error)
+            .build(),
+    };
+    write_frame_async(writer, &Message::to_bytes(&[reply], Encoding::Json)).await?;
+    match answer {
+        Ok((_, encoding)) => Ok(encoding),
+        Err(e) => {
+            writer.shutdown().await?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+async fn refuse_legacy(writer: &mut WriteHalf, reason: &str) -> io::Result<Encoding> {
+    write_frame_async(writer, &legacy::upgrade_notice()).await?;
+    writer.shutdown().await?;
+    Err(io::Error::new(io::ErrorKind::InvalidData, reason.to_string()))
+}
+
+// Works out from the first byte the client sends whether it is starting a TLS handshake
+async fn negotiate(client_socket: TcpStream, tls: &TlsMode) -> std::io::Result<(ReadHalf, WriteHalf, bool)> {
+    let acceptor = match tls {
+        TlsMode::Off => None,
+        TlsMode::Optional(acceptor) | TlsMode::Required(acceptor) => Some(acceptor),
+    };
+    let mut first = [0u8; 1];
+    if acceptor.is_some() {
+        timeout(TLS_HANDSHAKE_TIMEOUT, client_socket.peek(&mut first)).await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out waiting for the client"))??;
+    }
+    match acceptor {
+        Some(acceptor) if first[0] == HANDSHAKE_BYTE => {
+            let stream = timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(client_socket)).await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+            trace!("TLS handshake completed");
+            let (reader, writer) = tokio::io::split(stream);
+            Ok((Box::new(reader), Box::new(writer), true))
+        }
+        _ if matches!(tls, TlsMode::Required(_)) => {
+            let mut client_socket = client_socket;
+            let refusal = Message::builder()
+                .message("This server only accepts TLS connections, reconnect with --tls")
+                .message_type(MessageType::Error)
+                .build();
+            write_frame_async(&mut client_socket, &Message::to_bytes(&[refusal], Encoding::Json)).await?;
+            client_socket.shutdown().await?;
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "plaintext connections are not allowed"))
+        }
+        _ => {
+            let (reader, writer) = client_socket.into_split();
+            Ok((Box::new(reader), Box::new(writer), false))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> &'static str {
+    signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    "Ctrl-C"
+}
+
+// Tells every client why it is about to be disconnected, then closes its queue so the writer hangs
+// up once the notice and anything queued before it have gone out
+fn notify_shutdown(state: &ServerState, reason: &str) {
+    let notice = SharedFrame::new(vec![Message::builder()
+        .message(reason)
+        .message_type(MessageType::ServerShutdown)
+        .build()]);
+    for client in state.clients.lock().unwrap().iter() {
+        let outbound = client.outbound();
+        outbound.try_push(notice.clone());
+        outbound.close();
+    }
+}
+
+// The only place messages get copied out to other clients. It just hands one shared frame to each
+// recipient's outbound queue, so one stuck socket can stall delivery to everyone else for at most the overflow
+// policy's grace period.
+async fn fan_out(mut broadcast_queue: UnboundedReceiver<Broadcast>, state: Arc<ServerState>) {
+    while let Some(broadcast) = broadcast_queue.recv().await {
+        // Collect the queues first, pushing may wait and the lock must not be held across that
+        let recipients: Vec<Arc<OutboundQueue>> = state.clients.lock().unwrap().iter()
+            .filter(|client| client.accepts(&broadcast))
+            .map(|client| client.outbound())
+            .collect();
+        let frame = SharedFrame::new(vec![broadcast.message]);
+        for outbound in recipients {
+            outbound.push(frame.clone()).await;
+        }
+    }
+}
+
+async fn report_queues(state: Arc<ServerState>) {
+    let mut ticker = interval(QUEUE_REPORT_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let client_handlers = state.clients.lock().unwrap();
+        if client_handlers.is_empty() {
+            continue;
+        }
+        let mut total = 0;
+        let mut dropped = 0;
+        let mut deepest = (0, String::new());
+        let mut high_water = 0;
+        for client in client_handlers.iter() {
+            let stats = client.outbound().stats();
+            total += stats.depth;
+            dropped += stats.dropped;
+            high_water = high_water.max(stats.high_water);
+            if stats.depth > deepest.0 {
+                deepest = (stats.depth, client.client_name.clone());
+            }
+        }
+        info!("Outbound queues: {} clients, {} frames queued, deepest {} ({}), high water {}, {} dropped",
+              client_handlers.len(), total, deepest.0, deepest.1, high_water, dropped);
+    }
+}
@@ -1,40 +1,62 @@
+use std::io;
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
 
 const DISCOVERY_REQUEST: &str = "DISCOVER_CHAT_SERVER_REQUEST";
 const DISCOVERY_RESPONSE: &str = "DISCOVER_CHAT_SERVER_RESPONSE";
-const PORT: u16 = 8888;
+// How often the thread looks up from waiting to see whether the server has stopped
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct DiscoveryThread {
     socket: UdpSocket,
+    server_port: u16,
+    tls: bool,
 }
 
 impl DiscoveryThread {
-    pub fn new() -> Result<Self, std::io::Error> {
-        let socket = UdpSocket::bind(("0.0.0.0", PORT))?;
-        debug!("Opening Socket: {:?}", socket.local_addr().unwrap());
-        socket.set_broadcast(true).expect("Failed to set broadcast");
-        debug!("Enabled broadcast for socket: {:?}", socket.local_addr().unwrap());
+    pub fn new(port: u16, server_port: u16, tls: bool) -> Result<Self, std::io::Error> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        debug!("Opening Socket: {:?}", socket.local_addr()?);
+        socket.set_broadcast(true)?;
+        debug!("Enabled broadcast for socket: {:?}", socket.local_addr()?);
+        socket.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
         Ok(Self {
             socket,
+            server_port,
+            tls,
         })
     }
 
-    pub fn run(self) {
-        loop {
+    pub fn run(self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
             let mut buf = [0u8; 15000];
-            let (amt, src) = self.socket.recv_from(&mut buf)
-                .expect("Failed to receive packet");
+            let (amt, src) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                // Such as an ICMP error for an earlier reply, the socket itself is still fine
+                Err(e) => {
+                    warn!("Failed to receive discovery packet: {}", e);
+                    continue;
+                }
+            };
             trace!("Received packet from: {:?}", src);
             let message = String::from_utf8_lossy(&buf[..amt]);
             if message == DISCOVERY_REQUEST {
                 trace!("Received discovery request from: {:?}", src);
-                let response = DISCOVERY_RESPONSE.as_bytes();
-                self.socket.send_to(response, src)
-                    .expect("Failed to send response to client");
-                trace!("Sent discovery response to: {:?}", src);
+                // Tell the client which port the chat server is listening on, and whether it speaks TLS
+                let mut response = format!("{}:{}", DISCOVERY_RESPONSE, self.server_port);
+                if self.tls {
+                    response.push_str(":tls");
+                }
+                match self.socket.send_to(response.as_bytes(), src) {
+                    Ok(_) => trace!("Sent discovery response to: {:?}", src),
+                    Err(e) => warn!("Failed to send discovery response to {}: {}", src, e),
+                }
             }
         }
+        debug!("Discovery thread stopped");
     }
 }
@@ -0,0 +1,171 @@
+use std::io;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use log::trace;
+
+use quick_chat::{ChatClient, ChatError, Event, Message};
+
+use crate::commands;
+use crate::commands::{CommandRegistry, Console};
+
+const PASSWORD_VAR: &str = "QUICK_CHAT_PASSWORD";
+
+// Line based front end for when stdin or stdout is not a terminal, e.g. when piping into the
+// client: prints whatever the client hands over and sends what is read
+pub(crate) struct Terminal {
+    client: ChatClient,
+    commands: CommandRegistry,
+    running: bool,
+}
+
+impl Terminal {
+    pub(crate) fn new(client: ChatClient) -> Terminal {
+        let mut commands = CommandRegistry::new();
+        commands::register_builtin(&mut commands);
+
+        Terminal {
+            client,
+            commands,
+            running: true,
+        }
+    }
+
+    pub(crate) fn run(&mut self, events: Receiver<Event>) {
+        trace!("Terminal is running");
+        print_events(events);
+
+        let mut msg = String::new();
+        while self.running {
+            msg.clear();
+            let read = io::stdin()
+                .read_line(&mut msg)
+                .expect("Failed to read line");
+            msg = msg.trim().to_string();
+            if read == 0 || msg == "exit" {
+                self.quit();
+                break;
+            }
+            if let Some(command) = msg.strip_prefix('/') {
+                let result = self.commands.parse(command)
+                    .and_then(|(handler, args)| handler(self, &args));
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
+                continue;
+            }
+            if let Err(e) = self.client.send(&msg) {
+                println!("{}", e);
+            }
+        }
+    }
+}
+
+impl Console for Terminal {
+    fn client(&self) -> &ChatClient {
+        &self.client
+    }
+
+    fn commands(&self) -> &CommandRegistry {
+        &self.commands
+    }
+
+    fn print(&mut self, text: &str) {
+        for line in text.lines() {
+            println!("{}", line);
+        }
+    }
+
+    // What was typed is already on screen
+    fn echo(&mut self, _message: &Message) {}
+
+    fn clear(&mut self) {
+        print!("\x1B[2J\x1B[1;1H");
+    }
+
+    fn quit(&mut self) {
+        self.running = false;
+        self.client.close();
+    }
+}
+
+fn print_events(events: Receiver<Event>) {
+    thread::spawn(move || {
+        for event in events {
+            match event {
+                // There is /who for that
+                Event::Users(_) => {}
+                // Only ever after quitting, main is on its way out and may still have a server to stop
+                Event::Disconnected => {
+                    println!("{}", event);
+                    return;
+                }
+                event => println!("{}", event),
+            }
+        }
+    });
+}
+
+// Asks for a username until the server accepts one, before either front end starts. Returns false
+// if stdin ran out or the connection was lost first.
+pub(crate) fn pick_username(client: &ChatClient, mut requested_username: Option<String>, register: bool) -> bool {
+    loop {
+        let username = match requested_username.take() {
+            Some(username) => username,
+            None => {
+                print!("Enter username: ");
+                io::stdout().flush().expect("Failed to flush stdout");
+                let mut username = String::new();
+                if io::stdin().read_line(&mut username).unwrap_or(0) == 0 {
+                    return false;
+                }
+                username
+            }
+        };
+        match claim_username(client, username.trim(), register) {
+            Ok(()) => {
+                println!("Username set to {}", client.username());
+                return true;
+            }
+            Err(ChatError::Io(e)) => {
+                println!("{}", e);
+                return false;
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+// Takes the name as a guest, or registers it, or logs in if the server says it is an account
+fn claim_username(client: &ChatClient, username: &str, register: bool) -> Result<(), ChatError> {
+    let result = if register {
+        let password = read_password(client, &format!("Choose a password for {}: ", username), true)?;
+        client.register(username, &password)
+    } else {
+        client.set_username(username)
+    };
+    match result {
+        Err(ChatError::LoginRequired(registered)) => {
+            println!("[SERVER]: {} is a registered account, log in to use it", registered);
+            let password = read_password(client, &format!("Password for {}: ", registered), false)?;
+            client.login(&registered, &password)
+        }
+        result => result,
+    }
+}
+
+// QUICK_CHAT_PASSWORD saves typing it in, e.g. when scripting
+fn read_password(client: &ChatClient, prompt: &str, confirm: bool) -> Result<String, ChatError> {
+    if !client.is_tls() {
+        println!("Warning: the connection is not encrypted, your password can be read on the way. Use --tls");
+    }
+    if let Ok(password) = std::env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+    let password = rpassword::prompt_password(prompt)?;
+    if confirm && rpassword::prompt_password("Repeat the password: ")? != password {
+        return Err(ChatError::Rejected("Passwords do not match".to_string()));
+    }
+    Ok(password)
+}
@@ -0,0 +1,284 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::pki_types::pem::PemObject;
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const KNOWN_HOSTS_FILE: &str = "known_hosts";
+// Every TLS record starts with this content type, while a plaintext frame starts with the high byte
+// of a length that can never be this large
+pub(crate) const HANDSHAKE_BYTE: u8 = 0x16;
+
+// Where the server keeps its certificate and the client its pinned fingerprints
+pub(crate) fn config_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".quick_chat")
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Loads the certificate and key for [`TlsMode`](crate::TlsMode), by default from
+/// `~/.quick_chat`. A self-signed pair is generated there the first time.
+pub fn acceptor(cert: Option<PathBuf>, key: Option<PathBuf>) -> io::Result<TlsAcceptor> {
+    let cert = cert.unwrap_or_else(|| config_dir().join(CERT_FILE));
+    let key = key.unwrap_or_else(|| config_dir().join(KEY_FILE));
+    if !cert.exists() && !key.exists() {
+        generate_certificate(&cert, &key)?;
+    }
+    let certs = CertificateDer::pem_file_iter(&cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::other(format!("Could not read {}: {}", cert.display(), e)))?;
+    let key_der = PrivateKeyDer::from_pem_file(&key)
+        .map_err(|e| io::Error::other(format!("Could not read {}: {}", key.display(), e)))?;
+    if let Some(leaf) = certs.first() {
+        info!("TLS certificate fingerprint {}", fingerprint(leaf));
+    }
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn generate_certificate(cert: &Path, key: &Path) -> io::Result<()> {
+    info!("Generating a self-signed certificate in {}", cert.display());
+    let certified = rcgen::generate_simple_self_signed(vec!["quick-chat".to_string(), "localhost".to_string()])
+        .map_err(io::Error::other)?;
+    for path in [cert, key] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(cert, certified.cert.pem())?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(key)?.write_all(certified.key_pair.serialize_pem().as_bytes())
+}
+
+pub(crate) fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref()).iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+// Which certificate a server has to present
+pub(crate) enum Trust<'a> {
+    // The one known_hosts has for the address, pinned there the first time it is seen
+    FirstUse,
+    // Exactly this fingerprint, e.g. of the server a session started on, and nothing gets pinned
+    Pinned(&'a str),
+}
+
+// Opens a TLS session over `socket` and finishes the handshake before handing it back, so a
+// certificate that does not match the pin fails here rather than on the first read
+pub(crate) fn connect(socket: &mut TcpStream, address: &str, trust: Trust) -> io::Result<ClientConnection> {
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            address: address.to_string(),
+            known_hosts: config_dir().join(KNOWN_HOSTS_FILE),
+            expected: match trust {
+                Trust::FirstUse => None,
+                Trust::Pinned(fingerprint) => Some(fingerprint.to_string()),
+            },
+            provider: provider(),
+        }))
+        .with_no_client_auth();
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let server_name = ServerName::try_from(host.to_string())
+        .unwrap_or_else(|_| ServerName::try_from("quick-chat").unwrap());
+    let mut connection = ClientConnection::new(Arc::new(config), server_name)
+        .map_err(io::Error::other)?;
+    while connection.is_handshaking() {
+        connection.complete_io(socket)?;
+    }
+    debug!("TLS session with {} established", address);
+    Ok(connection)
+}
+
+// Trust on first use: the first certificate seen for an address is written to known_hosts and
+// every later connection to that address has to present the same one
+#[derive(Debug)]
+struct PinnedCertificate {
+    address: String,
+    known_hosts: PathBuf,
+    // Set when known_hosts is not to be consulted or written
+    expected: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedCertificate {
+    fn pinned(&self) -> Option<String> {
+        fs::read_to_string(&self.known_hosts).ok()?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(address, _)| *address == self.address)
+            .map(|(_, fingerprint)| fingerprint.trim().to_string())
+    }
+
+    fn pin(&self, fingerprint: &str) -> io::Result<()> {
+        if let Some(parent) = self.known_hosts.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.known_hosts)?;
+        writeln!(file, "{} {}", self.address, fingerprint)
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self,
+                          end_entity: &CertificateDer<'_>,
+                          _intermediates: &[CertificateDer<'_>],
+                          _server_name: &ServerName<'_>,
+                          _ocsp_response: &[u8],
+                          _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        if let Some(expected) = &self.expected {
+            return if *expected == fingerprint {
+                Ok(ServerCertVerified::assertion())
+            } else {
+                Err(rustls::Error::General(format!(
+                    "{} presented certificate {} rather than {} of the server this session started on",
+                    self.address, fingerprint, expected)))
+            };
+        }
+        match self.pinned() {
+            Some(pinned) if pinned == fingerprint => Ok(ServerCertVerified::assertion()),
+            Some(pinned) => Err(rustls::Error::General(format!(
+                "The certificate of {} changed from {} to {}. If that is expected, remove its line from {}",
+                self.address, pinned, fingerprint, self.known_hosts.display()))),
+            None => {
+                info!("Trusting {} on first use, certificate fingerprint {}", self.address, fingerprint);
+                self.pin(&fingerprint)
+                    .map_err(|e| rustls::Error::General(format!("Could not pin certificate: {}", e)))?;
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer<'_>,
+                              dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// The client reads on one thread and writes on another, which a rustls stream can't do on its own.
+// Both halves share the session and only hold its lock while moving bytes in or out of it, never
+// while waiting on the socket for more to arrive.
+pub(crate) struct TlsReader {
+    session: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+    // Bytes read off the socket that the session had no room for yet
+    pending: Vec<u8>,
+}
+
+pub(crate) struct TlsWriter {
+    session: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+}
+
+pub(crate) fn split(session: Arc<Mutex<ClientConnection>>, socket: &TcpStream) -> io::Result<(TlsReader, TlsWriter)> {
+    Ok((
+        TlsReader {
+            session: session.clone(),
+            socket: socket.try_clone()?,
+            pending: Vec::new(),
+        },
+        TlsWriter {
+            session,
+            socket: socket.try_clone()?,
+        },
+    ))
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                match session.reader().read(buf) {
+                    Ok(amt) => return Ok(amt),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                if !self.pending.is_empty() {
+                    let mut pending = self.pending.as_slice();
+                    session.read_tls(&mut pending)?;
+                    self.pending = pending.to_vec();
+                    session.process_new_packets().map_err(io::Error::other)?;
+                    while session.wants_write() {
+                        session.write_tls(&mut self.socket)?;
+                    }
+                    continue;
+                }
+            }
+            let mut received = [0u8; 16 * 1024];
+            let amt = self.socket.read(&mut received)?;
+            if amt == 0 {
+                return Ok(0);
+            }
+            self.pending.extend_from_slice(&received[..amt]);
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let amt = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut self.socket)?;
+        }
+        Ok(amt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        while session.wants_write() {
+            session.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+}
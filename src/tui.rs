@@ -0,0 +1,545 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+
+use log::trace;
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use unicode_width::UnicodeWidthChar;
+
+use quick_chat::{ChatClient, Event, Message, MessageType, Presence};
+
+use crate::commands;
+use crate::commands::{CommandRegistry, Console};
+
+// Oldest lines are dropped past this, so a long session doesn't grow without bound
+const MAX_LINES: usize = 5000;
+const MAX_INPUT_HISTORY: usize = 100;
+const USER_LIST_WIDTH: u16 = 24;
+// How long to wait for a key before looking for new messages again
+const TICK: Duration = Duration::from_millis(50);
+// Users idle for longer are dimmed in the sidebar
+const IDLE_SECS: u64 = 300;
+
+// While the full-screen interface is up log lines are shown in it, writing them to stdout would
+// scribble over the screen
+static LOG_LINES: Mutex<Option<Sender<String>>> = Mutex::new(None);
+
+// Log target for when the full-screen interface may be used. Writes to stdout until it starts.
+#[derive(Default)]
+pub(crate) struct LogWriter {
+    buffer: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sink = LOG_LINES.lock().unwrap();
+        let sink = match sink.as_ref() {
+            Some(sink) => sink,
+            None => return io::stdout().write(buf),
+        };
+        self.buffer.extend_from_slice(buf);
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let _ = sink.send(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Status {
+    Connected,
+    Reconnecting,
+    Disconnected,
+}
+
+// Full-screen front end: messages on the left, who is online on the right, the line being typed
+// and the connection state below
+pub(crate) struct Tui {
+    client: ChatClient,
+    commands: CommandRegistry,
+    running: bool,
+    status: Status,
+    lines: VecDeque<Line<'static>>,
+    // How many rows the message pane is scrolled up from the bottom, 0 follows new messages
+    scroll: usize,
+    // Rows the message pane showed last time it was drawn, what PageUp and PageDown move by
+    page_height: usize,
+    users: Vec<Presence>,
+    input: Input,
+}
+
+impl Tui {
+    pub(crate) fn new(client: ChatClient) -> Tui {
+        let mut commands = CommandRegistry::new();
+        commands::register_builtin(&mut commands);
+        let users = client.online_users();
+
+        Tui {
+            client,
+            commands,
+            running: true,
+            status: Status::Connected,
+            lines: VecDeque::new(),
+            scroll: 0,
+            page_height: 1,
+            users,
+            input: Input::default(),
+        }
+    }
+
+    pub(crate) fn run(mut self, events: Receiver<Event>) -> io::Result<()> {
+        trace!("Full-screen interface is running");
+        let (sender, logs) = channel();
+        *LOG_LINES.lock().unwrap() = Some(sender);
+        let result = ratatui::try_init().and_then(|mut terminal| self.event_loop(&mut terminal, &events, &logs));
+        ratatui::restore();
+        *LOG_LINES.lock().unwrap() = None;
+        // Whatever was logged after the last redraw would be lost otherwise
+        for line in logs.try_iter() {
+            println!("{}", line);
+        }
+        if self.status == Status::Disconnected {
+            println!("{}", Event::Disconnected);
+        }
+        result
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal, events: &Receiver<Event>, logs: &Receiver<String>)
+                  -> io::Result<()> {
+        let mut redraw = true;
+        while self.running {
+            for event in events.try_iter() {
+                self.handle_event(event);
+                redraw = true;
+            }
+            for line in logs.try_iter() {
+                self.push(Line::styled(line, Style::new().fg(Color::DarkGray)));
+                redraw = true;
+            }
+            if redraw {
+                terminal.draw(|frame| self.draw(frame))?;
+                redraw = false;
+            }
+            if event::poll(TICK)? {
+                if let event::Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        self.handle_key(key);
+                    }
+                }
+                // Resizes need a redraw too
+                redraw = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Message(message) => self.show(&message),
+            Event::Users(users) => self.users = users,
+            Event::Reconnecting => {
+                self.status = Status::Reconnecting;
+                self.notice(&event.to_string());
+            }
+            Event::Reconnected { .. } => {
+                self.status = Status::Connected;
+                self.notice(&event.to_string());
+            }
+            Event::ResumeFailed(_) => self.notice(&event.to_string()),
+            Event::Disconnected => {
+                self.status = Status::Disconnected;
+                self.running = false;
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if control => self.quit(),
+            KeyCode::Char('u') if control => self.input.clear(),
+            KeyCode::Char('a') if control => self.input.home(),
+            KeyCode::Char('e') if control => self.input.end(),
+            KeyCode::Char(c) if !control => self.input.insert(c),
+            KeyCode::Enter => self.submit(),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll += self.page_height.saturating_sub(1).max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page_height.saturating_sub(1).max(1)),
+            _ => {}
+        }
+    }
+
+    fn submit(&mut self) {
+        let line = self.input.submit();
+        if line.is_empty() {
+            return;
+        }
+        if line == "exit" {
+            self.quit();
+            return;
+        }
+        // Sending something is a good sign the user wants to see what comes next
+        self.scroll = 0;
+        let result = match line.strip_prefix('/') {
+            Some(command) => self.commands.parse(command)
+                .and_then(|(handler, args)| handler(self, &args)),
+            None => self.client.send(&line)
+                .map(|message| self.echo(&message))
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            self.error(&e);
+        }
+    }
+
+    fn show(&mut self, message: &Message) {
+        let style = match message.get_type() {
+            MessageType::Error | MessageType::LoginFailed => Style::new().fg(Color::Red),
+            MessageType::DirectMessage => Style::new().fg(Color::Magenta),
+            MessageType::Message | MessageType::Action if message.get_username() == self.client.username() => {
+                Style::new().fg(Color::Cyan)
+            }
+            MessageType::Message | MessageType::Action => Style::new(),
+            // Joins, leaves, renames and answers to requests
+            _ => Style::new().fg(Color::Yellow),
+        };
+        for line in message.to_string().lines() {
+            self.push(Line::styled(line.to_string(), style));
+        }
+    }
+
+    fn notice(&mut self, text: &str) {
+        self.push(Line::styled(text.to_string(), Style::new().fg(Color::Yellow)));
+    }
+
+    fn error(&mut self, text: &str) {
+        self.push(Line::styled(text.to_string(), Style::new().fg(Color::Red)));
+    }
+
+    fn push(&mut self, line: Line<'static>) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        // Keep what the user scrolled back to in view
+        if self.scroll > 0 {
+            self.scroll += 1;
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        let [messages_area, users_area] = Layout::horizontal([
+            Constraint::Min(20),
+            Constraint::Length(USER_LIST_WIDTH),
+        ]).areas(main);
+
+        self.draw_messages(frame, messages_area);
+        self.draw_users(frame, users_area);
+        self.draw_input(frame, input_area);
+        frame.render_widget(Paragraph::new(self.status_line()), status_area);
+    }
+
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        // Wrapped here rather than by the paragraph, scrolling needs to know how many rows there are
+        let width = area.width.saturating_sub(2) as usize;
+        let rows: Vec<Line> = self.lines.iter().flat_map(|line| wrap(line, width)).collect();
+        let height = area.height.saturating_sub(2) as usize;
+        let bottom = rows.len().saturating_sub(height);
+        self.scroll = self.scroll.min(bottom);
+        self.page_height = height;
+
+        let room = self.client.current_room().unwrap_or_else(|| "no room".to_string());
+        let title = if self.scroll > 0 {
+            format!(" {} (scrolled back, PageDown to return) ", room)
+        } else {
+            format!(" {} ", room)
+        };
+        let top = (bottom - self.scroll).min(u16::MAX as usize) as u16;
+        let paragraph = Paragraph::new(rows).block(Block::bordered().title(title)).scroll((top, 0));
+        frame.render_widget(paragraph, area);
+    }
+
+    fn draw_users(&self, frame: &mut Frame, area: Rect) {
+        let own_name = self.client.username();
+        let mut users: Vec<&Presence> = self.users.iter().collect();
+        users.sort_by_key(|user| user.username.to_lowercase());
+        let items: Vec<ListItem> = users.iter()
+            .map(|user| {
+                let style = if user.username == own_name {
+                    Style::new().add_modifier(Modifier::BOLD)
+                } else if user.idle_secs > IDLE_SECS {
+                    Style::new().fg(Color::DarkGray)
+                } else {
+                    Style::new()
+                };
+                ListItem::new(Line::styled(user.username.clone(), style))
+            })
+            .collect();
+        let title = format!(" Online ({}) ", users.len());
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_input(&self, frame: &mut Frame, area: Rect) {
+        let width = area.width.saturating_sub(2) as usize;
+        let before_cursor = Span::raw(self.input.before_cursor()).width();
+        // Slide the text left once the cursor would run off the end
+        let offset = (before_cursor + 1).saturating_sub(width);
+        let input = Paragraph::new(self.input.text.as_str())
+            .scroll((0, offset.min(u16::MAX as usize) as u16))
+            .block(Block::bordered().title(" /help for commands "));
+        frame.render_widget(input, area);
+        frame.set_cursor_position(Position::new(
+            area.x + 1 + (before_cursor - offset) as u16,
+            area.y + 1,
+        ));
+    }
+
+    fn status_line(&self) -> Line<'static> {
+        let (state, colour) = match self.status {
+            Status::Connected => ("Connected", Color::Green),
+            Status::Reconnecting => ("Reconnecting", Color::Yellow),
+            Status::Disconnected => ("Disconnected", Color::Red),
+        };
+        let mut spans = vec![
+            Span::styled(format!(" ● {} ", state), Style::new().fg(colour)),
+            Span::raw(format!("{} ", self.client.address())),
+        ];
+        if self.client.is_tls() {
+            spans.push(Span::styled("TLS ", Style::new().fg(Color::Green)));
+        } else {
+            spans.push(Span::styled("unencrypted ", Style::new().fg(Color::DarkGray)));
+        }
+        spans.push(Span::raw(format!("│ {}", self.client.username())));
+        Line::from(spans)
+    }
+}
+
+impl Console for Tui {
+    fn client(&self) -> &ChatClient {
+        &self.client
+    }
+
+    fn commands(&self) -> &CommandRegistry {
+        &self.commands
+    }
+
+    fn print(&mut self, text: &str) {
+        for line in text.lines() {
+            self.push(Line::raw(line.to_string()));
+        }
+    }
+
+    fn echo(&mut self, message: &Message) {
+        self.show(message);
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+        self.scroll = 0;
+    }
+
+    fn quit(&mut self) {
+        self.running = false;
+        self.client.close();
+    }
+}
+
+// The line being typed, plus earlier ones to go back to with the arrow keys
+#[derive(Default)]
+struct Input {
+    text: String,
+    // In characters, not bytes
+    cursor: usize,
+    history: VecDeque<String>,
+    // Which history entry is shown, None while editing a new line
+    browsing: Option<usize>,
+    // The new line, put back when arrowing down past the newest entry
+    draft: String,
+}
+
+impl Input {
+    fn byte_index(&self) -> usize {
+        self.text.char_indices()
+            .nth(self.cursor)
+            .map(|(index, _)| index)
+            .unwrap_or(self.text.len())
+    }
+
+    fn before_cursor(&self) -> &str {
+        &self.text[..self.byte_index()]
+    }
+
+    fn insert(&mut self, c: char) {
+        let index = self.byte_index();
+        self.text.insert(index, c);
+        self.cursor += 1;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            let index = self.byte_index();
+            self.text.remove(index);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.text.chars().count() {
+            let index = self.byte_index();
+            self.text.remove(index);
+        }
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.text.chars().count());
+    }
+
+    fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.text.chars().count();
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    fn replace(&mut self, text: String) {
+        self.text = text;
+        self.end();
+    }
+
+    fn previous(&mut self) {
+        let index = match self.browsing {
+            _ if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.text);
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1),
+        };
+        self.browsing = Some(index);
+        self.replace(self.history[index].clone());
+    }
+
+    fn next(&mut self) {
+        match self.browsing {
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.replace(self.history[index + 1].clone());
+            }
+            Some(_) => {
+                self.browsing = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.replace(draft);
+            }
+            None => {}
+        }
+    }
+
+    // Empties the line and returns what was on it
+    fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.text).trim().to_string();
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        if !line.is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == MAX_INPUT_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
+
+// Splits a line into rows at most `width` columns wide, breaking between words where it can and
+// inside a word only when it is wider than a row. Spaces a row breaks at are dropped.
+fn wrap(line: &Line<'static>, width: usize) -> Vec<Line<'static>> {
+    let width = width.max(1);
+    let chars: Vec<(char, Style)> = line.spans.iter()
+        .flat_map(|span| span.content.chars().map(move |c| (c, span.style)))
+        .collect();
+    let char_width = |c: char| c.width().unwrap_or(0);
+    let mut rows: Vec<Vec<(char, Style)>> = vec![Vec::new()];
+    let mut row_width = 0;
+    let mut start = 0;
+    while start < chars.len() {
+        if chars[start].0.is_whitespace() {
+            let space = char_width(chars[start].0);
+            if row_width + space > width {
+                rows.push(Vec::new());
+                row_width = 0;
+            } else {
+                rows.last_mut().unwrap().push(chars[start]);
+                row_width += space;
+            }
+            start += 1;
+            continue;
+        }
+        let end = chars[start..].iter()
+            .position(|(c, _)| c.is_whitespace())
+            .map_or(chars.len(), |length| start + length);
+        let word = &chars[start..end];
+        let word_width: usize = word.iter().map(|(c, _)| char_width(*c)).sum();
+        if row_width > 0 && row_width + word_width > width {
+            rows.push(Vec::new());
+            row_width = 0;
+        }
+        for &(c, style) in word {
+            let c_width = char_width(c);
+            if row_width > 0 && row_width + c_width > width {
+                rows.push(Vec::new());
+                row_width = 0;
+            }
+            rows.last_mut().unwrap().push((c, style));
+            row_width += c_width;
+        }
+        start = end;
+    }
+    rows.into_iter()
+        .map(|row| {
+            let mut spans: Vec<Span<'static>> = Vec::new();
+            for (c, style) in row {
+                match spans.last_mut() {
+                    Some(span) if span.style == style => span.content.to_mut().push(c),
+                    _ => spans.push(Span::styled(c.to_string(), style)),
+                }
+            }
+            Line::from(spans).style(line.style)
+        })
+        .collect()
+}
@@ -0,0 +1,85 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{MixedScript, skeleton};
+
+pub(crate) const MIN_LEN: usize = 3;
+pub(crate) const MAX_LEN: usize = 20;
+// Nobody gets to talk as these, registered or not
+const RESERVED_NAMES: &[&str] = &["SERVER"];
+
+// Checks a requested name and returns it the way it will be shown. NFKC folds the many ways of
+// encoding the same text, e.g. full width letters, into one.
+pub(crate) fn validate(username: &str) -> Result<String, String> {
+    let username: String = username.trim().nfkc().collect();
+    if username.is_empty() {
+        return Err("Username cannot be empty".to_string());
+    }
+    if !(MIN_LEN..=MAX_LEN).contains(&username.chars().count()) {
+        return Err(format!("Username has to be {} to {} characters long", MIN_LEN, MAX_LEN));
+    }
+    if !username.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        return Err("Username can only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+    // Mixing e.g. Latin and Cyrillic is how look-alikes of existing names get made
+    if !username.as_str().is_single_script() {
+        return Err("Username cannot mix letters from different scripts".to_string());
+    }
+    if is_reserved(&username) {
+        return Err(format!("{} is reserved", username));
+    }
+    Ok(username)
+}
+
+// Two names are the same user if their canonical forms match, so "alice", "Alice" and "aIice"
+// can't be told apart and only one of them can be in use
+pub(crate) fn canonical(username: &str) -> String {
+    let folded = skeleton(&username.nfkc().collect::<String>().to_lowercase())
+        .collect::<String>()
+        .to_lowercase();
+    // An upper case I looks like an l and case is ignored, so i has to match l as well
+    folded.replace('i', "l")
+}
+
+pub(crate) fn same_user(a: &str, b: &str) -> bool {
+    canonical(a) == canonical(b)
+}
+
+pub(crate) fn is_reserved(username: &str) -> bool {
+    RESERVED_NAMES.iter().any(|reserved| same_user(reserved, username))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_folds_to_nfkc() {
+        assert_eq!(validate("  ｂｏｂ ").unwrap(), "bob");
+        assert_eq!(validate("Alice_1.x-y").unwrap(), "Alice_1.x-y");
+        // Any one script is fine
+        assert_eq!(validate("алиса").unwrap(), "алиса");
+    }
+
+    #[test]
+    fn validate_rejects_bad_names() {
+        for username in ["", "   ", "ab", "abcdefghijklmnopqrstu", "bob!", "a b c", "аlice", "server", "Server"] {
+            assert!(validate(username).is_err(), "{:?} was accepted", username);
+        }
+    }
+
+    #[test]
+    fn look_alikes_are_the_same_user() {
+        // Case, a Cyrillic а, an upper case I for l and full width letters
+        for username in ["ALICE", "аlice", "aIice", "ａｌｉｃｅ"] {
+            assert!(same_user("alice", username), "{:?} is not alice", username);
+        }
+        assert_eq!(canonical("alice"), canonical("allce"));
+        assert!(same_user("rn", "m"));
+        assert!(is_reserved("SERVER"));
+    }
+
+    #[test]
+    fn different_names_stay_apart() {
+        assert!(!same_user("alice", "alicia"));
+        assert!(!same_user("bob", "bobby"));
+    }
+}